@@ -1,54 +1,240 @@
 use encoding_rs::GBK;
-use include_dir::{include_dir, Dir};
 use indexmap::IndexMap;
-use std::fmt::Write;
+use once_cell::sync::OnceCell;
+use sha2::{Digest, Sha256};
+use std::{
+    borrow::Cow,
+    fmt::Write,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use crate::{crypto, lua};
+use crate::{
+    bytecode::{self, op},
+    crypto::{self, CryptoProfile},
+    lua, Error, Libraries,
+};
 
-const BUILDIN_BUNDLED_LIBRARIES_DESC: &[&str] = include!("../static/bundle.txt");
-const BUILDIN_BUNDLED_LIBRARIES: Dir = include_dir!("$CARGO_MANIFEST_DIR/static/bundle");
+/// Bundled chunk, with its packed form encoded on first use
+#[derive(Debug)]
+pub(crate) struct Entry {
+    lua: Cow<'static, [u8]>,
+    /// Where encoded forms are kept across restarts, by hash of `lua` and the ciphers
+    cache: Option<Arc<Path>>,
+    crypto: CryptoProfile,
+    encoded: OnceCell<Vec<u8>>,
+    hex: OnceCell<String>,
+}
+
+impl Entry {
+    pub fn new(
+        lua: impl Into<Cow<'static, [u8]>>,
+        cache: Option<Arc<Path>>,
+        crypto: CryptoProfile,
+    ) -> Self {
+        Self {
+            lua: lua.into(),
+            cache,
+            crypto,
+            encoded: OnceCell::new(),
+            hex: OnceCell::new(),
+        }
+    }
+
+    pub fn lua(&self) -> &[u8] {
+        &self.lua
+    }
+
+    /// Compressed and encrypted
+    pub fn encoded(&self) -> Result<&[u8], Error> {
+        self.encoded
+            .get_or_try_init(|| match &self.cache {
+                Some(dir) => encode_cached(&self.lua, &self.crypto, dir),
+                None => encode(&self.lua, &self.crypto),
+            })
+            .map(Vec::as_slice)
+    }
+
+    fn hex(&self) -> Result<&str, Error> {
+        let data = self.encoded()?;
+        Ok(self.hex.get_or_init(|| hex::encode_upper(data)))
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Chunks encoded by this thread, to tell whether encoded forms are reused
+    pub(crate) static ENCODES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+fn encode(lua: &[u8], crypto: &CryptoProfile) -> Result<Vec<u8>, Error> {
+    #[cfg(test)]
+    ENCODES.with(|n| n.set(n.get() + 1));
+    let mut data = Vec::new();
+    crypto::compress(lua, &mut data).map_err(Error::Compression)?;
+    crypto.encrypt_ulib(&mut data);
+    Ok(data)
+}
+
+/// Encode through the cache, which is only an optimization so failing to write it is fine
+fn encode_cached(lua: &[u8], crypto: &CryptoProfile, dir: &Path) -> Result<Vec<u8>, Error> {
+    let mut hasher = Sha256::new();
+    hasher.update(crypto.id());
+    hasher.update([0]);
+    hasher.update(lua);
+    let path: PathBuf = dir.join(hex::encode(hasher.finalize()));
+    if let Ok(data) = fs::read(&path) {
+        return Ok(data);
+    }
+
+    let data = encode(lua, crypto)?;
+    let tmp = path.with_extension("tmp");
+    if fs::write(&tmp, &data).is_ok() {
+        let _ = fs::rename(&tmp, &path);
+    }
+    Ok(data)
+}
 
 pub struct Bundles {
-    entries: IndexMap<&'static str, Vec<u8>>,
+    entries: IndexMap<Cow<'static, str>, Arc<Entry>>,
+    crypto: CryptoProfile,
+    strip: bool,
 }
 
 impl Bundles {
     pub fn with_adaptor(adaptor: Vec<u8>) -> Self {
+        Self::with_libraries(adaptor, &Libraries::builtin())
+    }
+
+    /// Libraries share their encoded forms with every other build using them
+    ///
+    /// The bundle is encrypted with the crypto profile of `libraries`.
+    pub fn with_libraries(adaptor: Vec<u8>, libraries: &Libraries) -> Self {
+        let crypto = libraries.crypto().clone();
         let mut entries = IndexMap::with_capacity(43);
-        entries.insert("adaptor.lua", adaptor);
-
-        for filename in BUILDIN_BUNDLED_LIBRARIES_DESC {
-            let content = BUILDIN_BUNDLED_LIBRARIES
-                .get_file(filename)
-                .unwrap()
-                .contents();
-            entries.insert(filename, content.to_owned());
+        let adaptor = Entry::new(adaptor, None, crypto.clone());
+        entries.insert("adaptor.lua".into(), Arc::new(adaptor));
+
+        for (filename, entry) in libraries.entries() {
+            entries.insert(filename.clone(), entry.clone());
+        }
+
+        Self {
+            entries,
+            crypto,
+            strip: false,
         }
+    }
 
-        Self { entries }
+    /// Compressed and encrypted entries, in the form as they are packed
+    pub fn encoded_entries(&self) -> Result<Vec<(&str, &[u8])>, Error> {
+        self.entries
+            .iter()
+            .map(|(name, entry)| Ok((name.as_ref(), entry.encoded()?)))
+            .collect()
     }
 
-    pub fn pack(&self) -> Result<Vec<u8>, mlua::Error> {
+    pub fn pack(&self) -> Result<Vec<u8>, Error> {
         let mut s = String::new();
-        for (name, lua) in &self.entries {
-            let (name, _, _) = GBK.encode(name);
-            let name = hex::encode_upper(name);
-
-            let mut data = Vec::new();
-            crypto::compress(lua, &mut data).map_err(mlua::Error::external)?;
-            crypto::encrypt_ulib(&mut data);
-            let data = hex::encode_upper(&data);
+        for (name, entry) in &self.entries {
+            let (encoded, _, had_errors) = GBK.encode(name);
+            if had_errors {
+                return Err(Error::Encoding(format!("entry name {}", name)));
+            }
+            let name = hex::encode_upper(encoded);
+            let data = entry.hex()?;
 
             write!(s, r#"__U_Lib("{name}", "{data}")"#).unwrap();
             s.push('\n');
         }
 
-        let mut bytecode = lua::compile("loader", s)?;
-        crypto::encrypt_res(&mut bytecode);
+        let mut bytecode = lua::compile("loader", s, self.strip)?;
+        self.crypto.encrypt_res(&mut bytecode);
         Ok(bytecode)
     }
 
+    /// Recover entries from a packed bundle, by reading the loader instead of running it
+    pub fn unpack(packed: &[u8]) -> Result<Self, io::Error> {
+        Self::unpack_with(packed, &CryptoProfile::dream_maker())
+    }
+
+    /// Recover entries from a bundle packed with `crypto`
+    pub fn unpack_with(packed: &[u8], crypto: &CryptoProfile) -> Result<Self, io::Error> {
+        let mut chunk = packed.to_owned();
+        crypto.decrypt_res(&mut chunk);
+
+        let dump = bytecode::parse(&chunk)?;
+        let main = dump.main().ok_or_else(|| invalid("empty loader"))?;
+
+        // track what registers hold, the loader is a sequence of `__U_Lib(name, data)`
+        #[derive(Clone, Copy)]
+        enum Reg<'a> {
+            Global(&'a [u8]),
+            Str(&'a [u8]),
+        }
+        let mut regs: [Option<Reg>; 256] = [None; 256];
+
+        let mut entries = IndexMap::new();
+        for ins in &main.bc {
+            let a = usize::from(ins.a());
+            match ins.op() {
+                op::GGET => regs[a] = main.kstr(ins.d()).map(Reg::Global),
+                op::KSTR => regs[a] = main.kstr(ins.d()).map(Reg::Str),
+                op::CALL if matches!(regs[a], Some(Reg::Global(f)) if f == b"__U_Lib") => {
+                    let args = (regs.get(a + 1).copied(), regs.get(a + 2).copied());
+                    let (name, data) = match args {
+                        (Some(Some(Reg::Str(name))), Some(Some(Reg::Str(data)))) => (name, data),
+                        _ => return Err(invalid("unexpected arguments of __U_Lib")),
+                    };
+
+                    let name = hex::decode(name).map_err(invalid)?;
+                    let (name, _, _) = GBK.decode(&name);
+
+                    let mut data = hex::decode(data).map_err(invalid)?;
+                    crypto.decrypt_ulib(&mut data);
+                    if !crypto::is_compressed(&data) {
+                        return Err(invalid(format!("entry {} is not compressed", name)));
+                    }
+                    let mut lua = Vec::new();
+                    crypto::decompress(&data, &mut lua)?;
+
+                    let entry = Entry::new(lua, None, crypto.clone());
+                    entries.insert(Cow::Owned(name.into_owned()), Arc::new(entry));
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            entries,
+            crypto: crypto.clone(),
+            strip: false,
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.entries.get(name).map(|entry| entry.lua())
+    }
+
+    /// Entries in the order they are packed
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.entries
+            .iter()
+            .map(|(name, entry)| (name.as_ref(), entry.lua()))
+    }
+
+    /// Strip debug info from the loader when packing
+    pub fn strip_debug_info(&mut self, strip: bool) {
+        self.strip = strip;
+    }
+
     pub fn set_database(&mut self, bytecode: Vec<u8>) {
-        self.entries.insert("database.lua", bytecode);
+        let entry = Entry::new(bytecode, None, self.crypto.clone());
+        self.entries.insert("database.lua".into(), Arc::new(entry));
     }
 }
+
+fn invalid(err: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
@@ -0,0 +1,368 @@
+//! Reader of bytecode dumped by `string.dump` of LuaJIT 2.0
+
+use std::{io, ops::Range};
+
+const MAGIC: &[u8] = b"\x1bLJ";
+pub const VERSION: u8 = 1;
+
+const FLAG_BE: u32 = 0x01;
+const FLAG_STRIP: u32 = 0x02;
+
+/// Variable info types below it are builtin ones without a name
+const VARNAME_MAX: u8 = 7;
+
+/// Opcodes used by readers in this crate
+pub mod op {
+    /// Last one of comparisons and tests, which only read their operands
+    pub const ISF: u8 = 15;
+    pub const MOV: u8 = 16;
+    pub const KSTR: u8 = 37;
+    pub const USETV: u8 = 44;
+    pub const USETP: u8 = 47;
+    pub const GGET: u8 = 52;
+    pub const GSET: u8 = 53;
+    pub const TGETS: u8 = 55;
+    pub const TSETV: u8 = 57;
+    pub const TSETS: u8 = 58;
+    pub const TSETM: u8 = 60;
+    pub const CALL: u8 = 62;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Ins(u32);
+
+impl Ins {
+    pub fn op(self) -> u8 {
+        self.0 as u8
+    }
+
+    pub fn a(self) -> u8 {
+        (self.0 >> 8) as u8
+    }
+
+    pub fn b(self) -> u8 {
+        (self.0 >> 24) as u8
+    }
+
+    pub fn c(self) -> u8 {
+        (self.0 >> 16) as u8
+    }
+
+    pub fn d(self) -> u16 {
+        (self.0 >> 16) as u16
+    }
+}
+
+/// Garbage collected constant
+#[derive(Debug, Clone)]
+pub enum Kgc {
+    /// Prototype of a closure, dumped before its parent
+    Child,
+    /// Template table, contents skipped
+    Table,
+    /// FFI integer or complex number
+    Cdata,
+    Str(Vec<u8>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Proto {
+    /// Instructions, without the leading function header
+    pub bc: Vec<Ins>,
+    /// Constants in the order they are dumped
+    pub kgc: Vec<Kgc>,
+    /// Line of each instruction, empty if debug info is stripped
+    pub lines: Vec<usize>,
+    /// Where names of upvalues and local variables are in the dump
+    pub names: Vec<Range<usize>>,
+}
+
+impl Proto {
+    /// String constant referenced by the negated index of an instruction operand
+    pub fn kstr(&self, idx: u16) -> Option<&[u8]> {
+        let pos = self.kgc.len().checked_sub(usize::from(idx) + 1)?;
+        match &self.kgc[pos] {
+            Kgc::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn line(&self, pc: usize) -> Option<usize> {
+        self.lines.get(pc).copied()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Dump {
+    /// Prototypes in the order they are dumped, the main chunk is the last one
+    pub protos: Vec<Proto>,
+}
+
+impl Dump {
+    pub fn main(&self) -> Option<&Proto> {
+        self.protos.last()
+    }
+}
+
+pub fn parse(data: &[u8]) -> Result<Dump, io::Error> {
+    let mut r = Reader {
+        data,
+        pos: 0,
+        base: 0,
+    };
+
+    if r.bytes(MAGIC.len())? != MAGIC {
+        return Err(invalid("not LuaJIT bytecode"));
+    }
+    let version = r.byte()?;
+    if version != VERSION {
+        return Err(invalid(format!("unsupported bytecode version {}", version)));
+    }
+
+    let flags = r.uleb()?;
+    let strip = flags & FLAG_STRIP != 0;
+    let be = flags & FLAG_BE != 0;
+    if !strip {
+        let len = r.uleb()?;
+        r.bytes(len as usize)?;
+    }
+
+    let mut protos = Vec::new();
+    loop {
+        let len = r.uleb()? as usize;
+        if len == 0 {
+            break;
+        }
+
+        let base = r.pos;
+        let mut p = Reader {
+            data: r.bytes(len)?,
+            pos: 0,
+            base,
+        };
+        protos.push(p.proto(strip, be)?);
+    }
+
+    Ok(Dump { protos })
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    /// Offset of `data` in the whole dump
+    base: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], io::Error> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| invalid("truncated bytecode"))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, io::Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn uleb(&mut self) -> Result<u32, io::Error> {
+        let mut v = 0u32;
+        let mut shift = 0;
+        loop {
+            let b = self.byte()?;
+            if shift < 32 {
+                v |= u32::from(b & 0x7f) << shift;
+            }
+            if b < 0x80 {
+                return Ok(v);
+            }
+            shift += 7;
+        }
+    }
+
+    /// Lower half of a number constant, with the lowest bit of first byte as a tag
+    fn uleb33(&mut self) -> Result<(u32, bool), io::Error> {
+        let first = self.byte()?;
+        let tag = first & 1 != 0;
+        let mut v = u32::from(first >> 1);
+        if v >= 0x40 {
+            v &= 0x3f;
+            let mut shift = 6;
+            loop {
+                let b = self.byte()?;
+                if shift < 32 {
+                    v |= u32::from(b & 0x7f) << shift;
+                }
+                if b < 0x80 {
+                    break;
+                }
+                shift += 7;
+            }
+        }
+        Ok((v, tag))
+    }
+
+    fn proto(&mut self, strip: bool, be: bool) -> Result<Proto, io::Error> {
+        let _flags = self.byte()?;
+        let _numparams = self.byte()?;
+        let _framesize = self.byte()?;
+        let numuv = self.byte()?;
+        let numkgc = self.uleb()?;
+        let numkn = self.uleb()?;
+        let numbc = self.uleb()?;
+        let mut lineinfo = None;
+        if !strip {
+            let sizedbg = self.uleb()?;
+            if sizedbg != 0 {
+                let firstline = self.uleb()?;
+                let numline = self.uleb()?;
+                lineinfo = Some((firstline, numline));
+            }
+        }
+
+        let bc = self
+            .bytes(numbc as usize * 4)?
+            .chunks_exact(4)
+            .map(|b| {
+                let b: [u8; 4] = b.try_into().unwrap();
+                Ins(if be {
+                    u32::from_be_bytes(b)
+                } else {
+                    u32::from_le_bytes(b)
+                })
+            })
+            .collect();
+
+        self.bytes(usize::from(numuv) * 2)?;
+
+        let kgc = (0..numkgc).map(|_| self.kgc()).collect::<Result<_, _>>()?;
+
+        for _ in 0..numkn {
+            let (_lo, is_num) = self.uleb33()?;
+            if is_num {
+                let _hi = self.uleb()?;
+            }
+        }
+
+        // only line numbers and names are read from debug info
+        let lines = match lineinfo {
+            Some((firstline, numline)) => {
+                let size = match numline {
+                    0..=0xff => 1,
+                    0x100..=0xffff => 2,
+                    _ => 4,
+                };
+                self.bytes(numbc as usize * size)?
+                    .chunks_exact(size)
+                    .map(|b| {
+                        let mut buf = [0; 4];
+                        let delta = if be {
+                            buf[4 - size..].copy_from_slice(b);
+                            u32::from_be_bytes(buf)
+                        } else {
+                            buf[..size].copy_from_slice(b);
+                            u32::from_le_bytes(buf)
+                        };
+                        firstline
+                            .checked_add(delta)
+                            .map(|line| line as usize)
+                            .ok_or_else(|| invalid("line number overflow"))
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+            None => Vec::new(),
+        };
+
+        let mut names = Vec::new();
+        if lineinfo.is_some() {
+            for _ in 0..numuv {
+                names.push(self.name()?);
+            }
+            loop {
+                match self.data.get(self.pos) {
+                    None | Some(0) => break,
+                    Some(tp) if *tp < VARNAME_MAX => {
+                        self.byte()?;
+                    }
+                    Some(_) => names.push(self.name()?),
+                }
+                let _startpc = self.uleb()?;
+                let _endpc = self.uleb()?;
+            }
+        }
+
+        Ok(Proto {
+            bc,
+            kgc,
+            lines,
+            names,
+        })
+    }
+
+    /// NUL terminated name, without the terminator
+    fn name(&mut self) -> Result<Range<usize>, io::Error> {
+        let len = self.data[self.pos..]
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or_else(|| invalid("unterminated name"))?;
+        let start = self.base + self.pos;
+        self.bytes(len + 1)?;
+        Ok(start..start + len)
+    }
+
+    fn kgc(&mut self) -> Result<Kgc, io::Error> {
+        let tp = self.uleb()?;
+        match tp {
+            0 => Ok(Kgc::Child),
+            1 => {
+                let narray = self.uleb()?;
+                let nhash = self.uleb()?;
+                for _ in 0..narray {
+                    self.ktabk()?;
+                }
+                for _ in 0..nhash {
+                    self.ktabk()?;
+                    self.ktabk()?;
+                }
+                Ok(Kgc::Table)
+            }
+            2 | 3 => {
+                self.uleb()?;
+                self.uleb()?;
+                Ok(Kgc::Cdata)
+            }
+            4 => {
+                for _ in 0..4 {
+                    self.uleb()?;
+                }
+                Ok(Kgc::Cdata)
+            }
+            _ => Ok(Kgc::Str(self.bytes(tp as usize - 5)?.to_owned())),
+        }
+    }
+
+    fn ktabk(&mut self) -> Result<(), io::Error> {
+        match self.uleb()? {
+            0..=2 => {}
+            3 => {
+                self.uleb()?;
+            }
+            4 => {
+                self.uleb()?;
+                self.uleb()?;
+            }
+            tp => {
+                self.bytes(tp as usize - 5)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
@@ -0,0 +1,233 @@
+use std::{error::Error, fs, path::PathBuf};
+
+use clap::Args;
+use dream_tutor::{
+    crypto::{self, CryptoProfile},
+    Bundles,
+};
+use time::{format_description, PrimitiveDateTime};
+
+use crate::{build_artifact, now, BuildSettings, CompileOption, GameType};
+
+#[derive(Debug, Args)]
+pub struct BuildArgs {
+    /// Game database, either compressed as DreamMaker uploads or not
+    input: PathBuf,
+    /// Where the packed game is written, `<FILENAME>.res` by default
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+    /// Name of the game, stem of the input by default
+    #[clap(long)]
+    filename: Option<String>,
+    /// Illegal keywords checked by the runtime
+    #[clap(long, default_value = "")]
+    keywords: String,
+    /// Report launches to the statistics hook
+    #[clap(long)]
+    statistics: bool,
+    /// Protect memory from cheating
+    #[clap(long)]
+    safedata: bool,
+    /// Protect from speed hacking
+    #[clap(long)]
+    jiasu: bool,
+    /// Build for runtimes with their driver enabled
+    #[clap(long)]
+    qudong: bool,
+    #[clap(long, value_enum, default_value = "offline")]
+    game_type: GameType,
+    #[clap(long, default_value_t = 1)]
+    ver: u32,
+    /// Uid of the server user whose resources auto update games fetch, required to build them
+    #[clap(long)]
+    owner: Option<u32>,
+    /// Build byte-identical output from identical inputs, see `build.reproducible`
+    #[clap(long)]
+    reproducible: bool,
+    /// Leave debug info out, see `build.strip`
+    #[clap(long)]
+    strip: bool,
+    /// Encrypt strings and rename locals of the database, see `build.obfuscate`
+    #[clap(long)]
+    obfuscate: bool,
+    /// `YYYY-MM-DD hh:mm:ss` in UTC stamped into the game, now by default
+    #[clap(long, value_parser = parse_build_time)]
+    build_time: Option<PrimitiveDateTime>,
+}
+
+impl BuildArgs {
+    pub fn run(self, mut settings: BuildSettings) -> Result<(), Box<dyn Error>> {
+        settings.reproducible |= self.reproducible;
+        settings.strip |= self.strip;
+        settings.obfuscate |= self.obfuscate;
+
+        let mut database = fs::read(&self.input)
+            .map_err(|err| format!("failed to read {}: {}", self.input.display(), err))?;
+        if crypto::is_compressed(&database) {
+            let mut buf = Vec::new();
+            crypto::decompress_with_limit(&database, &mut buf, settings.max_database_size)?;
+            database = buf;
+        }
+
+        let filename = match self.filename {
+            Some(filename) => filename,
+            None => self
+                .input
+                .file_stem()
+                .ok_or("no filename given")?
+                .to_string_lossy()
+                .into_owned(),
+        };
+        let output = self
+            .output
+            .unwrap_or_else(|| PathBuf::from(format!("{}.res", filename)));
+
+        let option = CompileOption {
+            name: filename.clone(),
+            filename,
+            op_safedata: self.safedata,
+            op_delad: false,
+            op_statistics: self.statistics,
+            op_jiasu: self.jiasu,
+            op_keywords: self.keywords,
+            op_qudong: self.qudong,
+            op_login: self.game_type,
+            ver: self.ver,
+        };
+
+        let build_time = self
+            .build_time
+            .unwrap_or_else(|| settings.build_time(now()));
+        // resources of auto update games are only published by the server, and launches are
+        // only counted for its tasks
+        let artifact = build_artifact(&database, &option, build_time, self.owner, None, &settings);
+        for warning in &artifact.warnings {
+            eprintln!("warning: {}", warning);
+        }
+        fs::write(&output, artifact.result?)
+            .map_err(|err| format!("failed to write {}: {}", output.display(), err))?;
+
+        println!("{} built into {}", option.filename, output.display());
+        Ok(())
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct UnpackArgs {
+    /// Packed game, either compressed as downloaded from the server or not
+    input: PathBuf,
+    /// Directory the entries are written into, created if missing
+    #[clap(short, long, default_value = ".")]
+    output: PathBuf,
+}
+
+impl UnpackArgs {
+    pub fn run(self, crypto: &CryptoProfile) -> Result<(), Box<dyn Error>> {
+        let mut packed = fs::read(&self.input)
+            .map_err(|err| format!("failed to read {}: {}", self.input.display(), err))?;
+        if crypto::is_compressed(&packed) {
+            let mut buf = Vec::new();
+            crypto::decompress(&packed, &mut buf)?;
+            packed = buf;
+        }
+
+        let bundles = Bundles::unpack_with(&packed, crypto)?;
+        fs::create_dir_all(&self.output)?;
+        for (name, lua) in bundles.iter() {
+            // names come from the game, never let them escape the output directory
+            let file: String = name
+                .chars()
+                .map(|c| match c {
+                    '/' | '\\' | ':' => '_',
+                    c => c,
+                })
+                .collect();
+            if file.is_empty() || file.chars().all(|c| c == '.') {
+                return Err(format!("invalid entry name {:?}", name).into());
+            }
+
+            let path = self.output.join(file);
+            fs::write(&path, lua)
+                .map_err(|err| format!("failed to write {}: {}", path.display(), err))?;
+            println!("{}", path.display());
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_build_time(s: &str) -> Result<PrimitiveDateTime, String> {
+    let fmt = format_description::parse("[year]-[month]-[day] [hour]:[minute]:[second]")
+        .map_err(|err| err.to_string())?;
+    PrimitiveDateTime::parse(s, &fmt).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Debug, Parser)]
+    struct Cli {
+        #[clap(flatten)]
+        args: BuildArgs,
+    }
+
+    fn build(args: &[&str]) -> Result<(), Box<dyn Error>> {
+        let cli = Cli::try_parse_from(["build"].iter().chain(args)).unwrap();
+        cli.args.run(BuildSettings::default())
+    }
+
+    #[test]
+    fn build_into_output() {
+        if !crate::luajit_2_0() {
+            return;
+        }
+        let dir = std::env::temp_dir().join(format!("dream-tutor-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // compressed as DreamMaker uploads
+        let mut database = vec![0; 0x200];
+        database.extend_from_slice(b"local a = 1");
+        let mut uploaded = Vec::new();
+        crypto::compress(&database, &mut uploaded).unwrap();
+        let input = dir.join("123.dat");
+        fs::write(&input, &uploaded).unwrap();
+
+        let input = input.to_str().unwrap();
+        let output = dir.join("out.res");
+        let args = [
+            input,
+            "--output",
+            output.to_str().unwrap(),
+            "--build-time",
+            "2022-07-01 00:00:00",
+            "--reproducible",
+        ];
+        build(&args).unwrap();
+
+        let packed = fs::read(&output).unwrap();
+        assert!(crypto::is_compressed(&packed));
+        let mut buf = Vec::new();
+        crypto::decompress(&packed, &mut buf).unwrap();
+        let bundles = Bundles::unpack(&buf).unwrap();
+        assert!(bundles.get("database.lua").is_some());
+        assert!(bundles.get("adaptor.lua").is_some());
+
+        // the same inputs build the same game
+        build(&args).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), packed);
+
+        // auto update games fetch resources published for their owner
+        let output = dir.join("update.res");
+        let args = [input, "--output", output.to_str().unwrap()];
+        let err = build(&[&args[..], &["--game-type", "auto-update"]].concat()).unwrap_err();
+        assert!(err.to_string().contains("owner"), "{}", err);
+        assert!(!output.exists());
+        build(&[&args[..], &["--game-type", "auto-update", "--owner", "1"]].concat()).unwrap();
+        assert!(output.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
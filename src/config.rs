@@ -0,0 +1,508 @@
+use std::{
+    error::Error,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use dream_tutor::{
+    crypto::{CryptoProfile, Rc4, RESOURCE_KEY, ULIB_KEY},
+    Libraries, Linter,
+};
+use serde::{de, Deserialize, Deserializer};
+use tracing::metadata::LevelFilter;
+
+use crate::{worker::QueueSettings, BuildSettings};
+
+/// Read when no config file is given explicitly and it exists
+pub const DEFAULT_PATH: &str = "dream-tutor.toml";
+
+/// Settings of a server instance, read from a TOML file and overridden by `DREAM_TUTOR_*`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub log: LogConfig,
+    pub storage: StorageConfig,
+    pub users: UsersConfig,
+    pub build: BuildSettings,
+    pub queue: QueueSettings,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: SocketAddr,
+    /// Seconds before a request is aborted
+    pub timeout: u64,
+    /// Requests handled at the same time, the others are rejected
+    pub concurrency: usize,
+    /// Largest body of an uploaded game in bytes, still compressed
+    pub max_upload: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: ([0, 0, 0, 0], 3000).into(),
+            timeout: 10,
+            concurrency: 1024,
+            max_upload: 16 << 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {s}")),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    #[serde(deserialize_with = "from_str")]
+    pub level: LevelFilter,
+    pub format: LogFormat,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: LevelFilter::INFO,
+            format: LogFormat::Text,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    Disk,
+    /// Nothing is written to disk, useful for trying out
+    Memory,
+}
+
+impl FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disk" => Ok(StorageBackend::Disk),
+            "memory" => Ok(StorageBackend::Memory),
+            _ => Err(format!("unknown storage backend {s}")),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    /// Directory of disk storage
+    pub path: PathBuf,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            backend: StorageBackend::Disk,
+            path: "data".into(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UsersConfig {
+    /// Account file, `users.json` under the storage directory by default
+    pub path: Option<PathBuf>,
+}
+
+/// Changes to the libraries bundled into every game
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LibrarySettings {
+    /// Directory of libraries replacing the builtin ones of the same name, or added
+    pub overlay: Option<PathBuf>,
+    /// Builtin libraries left out
+    pub remove: Vec<String>,
+    /// Directory keeping compressed and encrypted libraries across restarts
+    pub cache: Option<PathBuf>,
+}
+
+impl LibrarySettings {
+    pub fn load(&self, crypto: CryptoProfile) -> Result<Libraries, String> {
+        let mut libraries = Libraries::builtin();
+        libraries.set_crypto(crypto);
+        if let Some(dir) = &self.cache {
+            libraries
+                .cache_dir(dir)
+                .map_err(|err| format!("failed to create {}: {}", dir.display(), err))?;
+        }
+        if let Some(dir) = &self.overlay {
+            libraries
+                .overlay(dir)
+                .map_err(|err| format!("failed to read libraries in {}: {}", dir.display(), err))?;
+        }
+        for name in &self.remove {
+            if !libraries.remove(name) {
+                return Err(format!("library {} to remove is not bundled", name));
+            }
+        }
+        Ok(libraries)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CipherKind {
+    Rc4,
+    /// Only available if built with the `chacha20` feature
+    ChaCha20,
+}
+
+/// Ciphers games are encrypted with, which have to match the bundled runtime
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CryptoSettings {
+    pub cipher: CipherKind,
+    /// Key of the loader in hex, the one of DreamMaker by default
+    pub resource_key: Option<String>,
+    /// Key of bundled entries in hex, the one of DreamMaker by default
+    pub ulib_key: Option<String>,
+    /// Nonce of ChaCha20 in hex
+    pub nonce: Option<String>,
+}
+
+impl Default for CryptoSettings {
+    fn default() -> Self {
+        Self {
+            cipher: CipherKind::Rc4,
+            resource_key: None,
+            ulib_key: None,
+            nonce: None,
+        }
+    }
+}
+
+impl CryptoSettings {
+    pub fn profile(&self) -> Result<CryptoProfile, String> {
+        let key = |key: &Option<String>, name: &str, default: &[u8]| match key {
+            Some(key) => hex::decode(key).map_err(|err| format!("invalid {}: {}", name, err)),
+            None => Ok(default.to_owned()),
+        };
+        let resource_key = key(&self.resource_key, "resource key", RESOURCE_KEY)?;
+        let ulib_key = key(&self.ulib_key, "ulib key", ULIB_KEY)?;
+
+        match self.cipher {
+            CipherKind::Rc4 => {
+                let resource = Rc4::new(&resource_key).map_err(|err| err.to_string())?;
+                let ulib = Rc4::new(&ulib_key).map_err(|err| err.to_string())?;
+                Ok(CryptoProfile::new(resource, ulib))
+            }
+            #[cfg(feature = "chacha20")]
+            CipherKind::ChaCha20 => {
+                use dream_tutor::crypto::ChaCha20;
+
+                let nonce = self.nonce.as_ref().ok_or("ChaCha20 requires a nonce")?;
+                let nonce = hex::decode(nonce).map_err(|err| format!("invalid nonce: {}", err))?;
+                let resource =
+                    ChaCha20::new(&resource_key, &nonce).map_err(|err| err.to_string())?;
+                let ulib = ChaCha20::new(&ulib_key, &nonce).map_err(|err| err.to_string())?;
+                Ok(CryptoProfile::new(resource, ulib))
+            }
+            #[cfg(not(feature = "chacha20"))]
+            CipherKind::ChaCha20 => Err("built without the chacha20 feature".to_owned()),
+        }
+    }
+}
+
+impl Config {
+    /// Read `path`, or `DEFAULT_PATH` if exists, then apply environment overrides
+    pub fn load(path: Option<&Path>) -> Result<Self, Box<dyn Error>> {
+        let path = path.or_else(|| {
+            let default = Path::new(DEFAULT_PATH);
+            default.exists().then_some(default)
+        });
+
+        let mut config: Config = match path {
+            Some(path) => {
+                let s = std::fs::read_to_string(path)
+                    .map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+                toml::from_str(&s).map_err(|err| format!("{}: {}", path.display(), err))?
+            }
+            None => Config::default(),
+        };
+
+        config.apply_env(|key| std::env::var(key).ok())?;
+        let crypto = config.build.crypto.profile()?;
+        config.build.bundled = config.build.libraries.load(crypto)?;
+        config.build.linter = Linter::new(&config.build.bundled)
+            .known_globals(config.build.known_globals.iter().map(String::as_str));
+        Ok(config)
+    }
+
+    /// Override settings by variables `var` looks up
+    fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), String> {
+        let env = Env(var);
+        if let Some(bind) = env.get("DREAM_TUTOR_BIND")? {
+            self.server.bind = bind;
+        }
+        if let Some(timeout) = env.get("DREAM_TUTOR_TIMEOUT")? {
+            self.server.timeout = timeout;
+        }
+        if let Some(concurrency) = env.get("DREAM_TUTOR_CONCURRENCY")? {
+            self.server.concurrency = concurrency;
+        }
+        if let Some(size) = env.get("DREAM_TUTOR_MAX_UPLOAD")? {
+            self.server.max_upload = size;
+        }
+        if let Some(level) = env.get("DREAM_TUTOR_LOG")? {
+            self.log.level = level;
+        }
+        if let Some(format) = env.get("DREAM_TUTOR_LOG_FORMAT")? {
+            self.log.format = format;
+        }
+        if let Some(backend) = env.get("DREAM_TUTOR_STORAGE")? {
+            self.storage.backend = backend;
+        }
+        if let Some(path) = env.get("DREAM_TUTOR_DATA")? {
+            self.storage.path = path;
+        }
+        if let Some(path) = env.get("DREAM_TUTOR_USERS")? {
+            self.users.path = Some(path);
+        }
+        if let Some(server) = env.get::<String>("DREAM_TUTOR_LOGIN_SERVER")? {
+            let server = parse_login_server(&server)
+                .ok_or("DREAM_TUTOR_LOGIN_SERVER should be `host:port`")?;
+            self.build.login_server = Some(server);
+        }
+        if let Some(url) = env.get("DREAM_TUTOR_PUBLIC_URL")? {
+            self.build.public_url = url;
+        }
+        if let Some(ver) = env.get("DREAM_TUTOR_ENGINE_VERSION")? {
+            self.build.engine_version = Some(ver);
+        }
+        if let Some(dir) = env.get("DREAM_TUTOR_LIBRARIES")? {
+            self.build.libraries.overlay = Some(dir);
+        }
+        if let Some(key) = env.get("DREAM_TUTOR_RESOURCE_KEY")? {
+            self.build.crypto.resource_key = Some(key);
+        }
+        if let Some(key) = env.get("DREAM_TUTOR_ULIB_KEY")? {
+            self.build.crypto.ulib_key = Some(key);
+        }
+        if let Some(strip) = env.flag("DREAM_TUTOR_STRIP")? {
+            self.build.strip = strip;
+        }
+        if let Some(obfuscate) = env.flag("DREAM_TUTOR_OBFUSCATE")? {
+            self.build.obfuscate = obfuscate;
+        }
+        if let Some(response) = env.get("DREAM_TUTOR_CHEAT_RESPONSE")? {
+            self.build.cheat_response = response;
+        }
+        if let Some(size) = env.get("DREAM_TUTOR_MAX_DATABASE_SIZE")? {
+            self.build.max_database_size = size;
+        }
+        if let Some(reproducible) = env.flag("DREAM_TUTOR_REPRODUCIBLE")? {
+            self.build.reproducible = reproducible;
+        }
+        // convention of reproducible builds,
+        // see https://reproducible-builds.org/specs/source-date-epoch/
+        if let Some(epoch) = env.get("SOURCE_DATE_EPOCH")? {
+            self.build.source_date_epoch = epoch;
+        }
+        if let Some(workers) = env.get("DREAM_TUTOR_WORKERS")? {
+            self.queue.workers = workers;
+        }
+        if let Some(depth) = env.get("DREAM_TUTOR_QUEUE_DEPTH")? {
+            self.queue.depth = depth;
+        }
+
+        Ok(())
+    }
+
+    pub fn users_path(&self) -> PathBuf {
+        self.users
+            .path
+            .clone()
+            .unwrap_or_else(|| self.storage.path.join("users.json"))
+    }
+}
+
+/// Environment variables as `Fn(key) -> value`
+struct Env<F>(F);
+
+impl<F: Fn(&str) -> Option<String>> Env<F> {
+    fn get<T: FromStr>(&self, key: &str) -> Result<Option<T>, String> {
+        match (self.0)(key) {
+            Some(value) => value
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("invalid value of {key}: {value}")),
+            None => Ok(None),
+        }
+    }
+
+    /// Switches are `true` or `1`, and `false` or `0`
+    fn flag(&self, key: &str) -> Result<Option<bool>, String> {
+        match (self.0)(key).as_deref() {
+            Some("true" | "1") => Ok(Some(true)),
+            Some("false" | "0") => Ok(Some(false)),
+            Some(value) => Err(format!(
+                "invalid value of {key}: {value}, expected true, false, 1 or 0"
+            )),
+            None => Ok(None),
+        }
+    }
+}
+
+fn parse_login_server(s: &str) -> Option<(String, u16)> {
+    let (host, port) = s.rsplit_once(':')?;
+    Some((host.to_owned(), port.parse().ok()?))
+}
+
+fn from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(de::Error::custom)
+}
+
+/// `host:port` of login server
+pub fn login_server<'de, D>(deserializer: D) -> Result<Option<(String, u16)>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    parse_login_server(&s)
+        .map(Some)
+        .ok_or_else(|| de::Error::custom("login server should be `host:port`"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn vars<'a>(vars: &'a [(&str, &str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        let vars: HashMap<_, _> = vars.iter().copied().collect();
+        move |key| vars.get(key).map(|value| value.to_string())
+    }
+
+    #[test]
+    fn load_config_file() {
+        let path = std::env::temp_dir().join(format!("dream-tutor-{}.toml", uuid::Uuid::new_v4()));
+        let toml = r#"
+            [server]
+            bind = "127.0.0.1:8080"
+
+            [log]
+            level = "debug"
+            format = "json"
+
+            [storage]
+            backend = "memory"
+
+            [build]
+            login_server = "10.0.0.1:6000"
+            strip = true
+
+            [queue]
+            workers = 3
+        "#;
+        std::fs::write(&path, toml).unwrap();
+        let config = Config::load(Some(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.server.bind, ([127, 0, 0, 1], 8080).into());
+        assert_eq!(config.server.timeout, ServerConfig::default().timeout);
+        assert_eq!(config.log.level, LevelFilter::DEBUG);
+        assert_eq!(config.log.format, LogFormat::Json);
+        assert_eq!(config.storage.backend, StorageBackend::Memory);
+        assert_eq!(config.users_path(), Path::new("data/users.json"));
+        assert_eq!(
+            config.build.login_server,
+            Some(("10.0.0.1".to_owned(), 6000))
+        );
+        assert!(config.build.strip);
+        assert_eq!(config.queue.workers, 3);
+
+        assert!(Config::load(Some(Path::new("/nonexistent/dream-tutor.toml"))).is_err());
+    }
+
+    #[test]
+    fn reject_invalid_config() {
+        let invalid = [
+            "[server]\nport = 3000",
+            "[server]\nbind = \"localhost\"",
+            "[log]\nlevel = \"loud\"",
+            "[storage]\nbackend = \"cloud\"",
+            "[build]\nlogin_server = \"10.0.0.1\"",
+            "[build]\nstrip = \"yes\"",
+        ];
+        for toml in invalid {
+            assert!(toml::from_str::<Config>(toml).is_err(), "{}", toml);
+        }
+    }
+
+    #[test]
+    fn apply_env_overrides() {
+        let mut config = Config::default();
+        config
+            .apply_env(vars(&[
+                ("DREAM_TUTOR_BIND", "127.0.0.1:8080"),
+                ("DREAM_TUTOR_STORAGE", "memory"),
+                ("DREAM_TUTOR_USERS", "accounts.json"),
+                ("DREAM_TUTOR_LOGIN_SERVER", "10.0.0.1:6000"),
+                ("DREAM_TUTOR_STRIP", "1"),
+                ("DREAM_TUTOR_OBFUSCATE", "true"),
+                ("DREAM_TUTOR_REPRODUCIBLE", "0"),
+                ("SOURCE_DATE_EPOCH", "1656633600"),
+            ]))
+            .unwrap();
+        assert_eq!(config.server.bind, ([127, 0, 0, 1], 8080).into());
+        assert_eq!(config.storage.backend, StorageBackend::Memory);
+        assert_eq!(config.users_path(), Path::new("accounts.json"));
+        assert_eq!(
+            config.build.login_server,
+            Some(("10.0.0.1".to_owned(), 6000))
+        );
+        assert!(config.build.strip);
+        assert!(config.build.obfuscate);
+        assert!(!config.build.reproducible);
+        assert_eq!(config.build.source_date_epoch, 1656633600);
+
+        let invalid = [
+            ("DREAM_TUTOR_TIMEOUT", "soon"),
+            ("DREAM_TUTOR_LOG_FORMAT", "xml"),
+            ("DREAM_TUTOR_LOGIN_SERVER", "10.0.0.1"),
+            ("DREAM_TUTOR_STRIP", "yes"),
+        ];
+        for (key, value) in invalid {
+            let err = Config::default()
+                .apply_env(vars(&[(key, value)]))
+                .unwrap_err();
+            assert!(err.contains(key), "{}", err);
+        }
+    }
+}
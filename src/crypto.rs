@@ -1,55 +1,505 @@
-use flate2::bufread::{ZlibDecoder, ZlibEncoder};
-use flate2::Compression;
-use rc4::Rc4;
-use rc4::{consts::*, KeyInit, StreamCipher};
-use std::io::{self, Cursor, Read};
+use flate2::{bufread::ZlibDecoder, write::ZlibEncoder, Compression};
+use once_cell::sync::Lazy;
+use rc4::{
+    cipher::generic_array::{ArrayLength, GenericArray},
+    KeyInit, StreamCipher,
+};
+use std::{
+    fmt,
+    io::{self, BufRead, Read, Write},
+    sync::Arc,
+};
 
-const RESOURCE_KEY: &[u8] = b"_Npi_dest__cc_&%_23";
-const ULIB_KEY: &[u8] = b"&!!__kl_\xB2\xE2_I_0";
+/// Key of the loader of DreamMaker games
+pub const RESOURCE_KEY: &[u8] = b"_Npi_dest__cc_&%_23";
+/// Key of entries bundled into DreamMaker games
+pub const ULIB_KEY: &[u8] = b"&!!__kl_\xB2\xE2_I_0";
+
+static DREAM_MAKER: Lazy<CryptoProfile> =
+    Lazy::new(|| CryptoProfile::new(Rc4::new(RESOURCE_KEY).unwrap(), Rc4::new(ULIB_KEY).unwrap()));
+
+/// Stream cipher, which encrypts and decrypts the same way
+pub trait Cipher: fmt::Debug + Send + Sync {
+    /// Apply the keystream from its start to `data`
+    fn apply_keystream(&self, data: &mut [u8]);
+
+    /// Tell apart ciphers and keys, encoded entries are cached by it
+    fn id(&self) -> String;
+}
+
+#[derive(Clone)]
+pub struct Rc4 {
+    key: Vec<u8>,
+    /// `rc4_apply` of the key length, as the crate takes the length as a type
+    apply: Rc4Apply,
+}
+
+impl Rc4 {
+    /// Key of 1 to 256 bytes
+    pub fn new(key: &[u8]) -> Result<Self, io::Error> {
+        let apply = key
+            .len()
+            .checked_sub(1)
+            .and_then(|i| RC4_APPLY.get(i))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("RC4 key of {} bytes", key.len()),
+                )
+            })?;
+        Ok(Self {
+            key: key.to_owned(),
+            apply: *apply,
+        })
+    }
+}
+
+/// Apply the keystream of a key to data
+type Rc4Apply = fn(&[u8], &mut [u8]);
+
+fn rc4_apply<N: ArrayLength<u8>>(key: &[u8], data: &mut [u8]) {
+    let mut rc4 = rc4::Rc4::<N>::new(GenericArray::from_slice(key));
+    rc4.apply_keystream(data);
+}
+
+macro_rules! rc4_apply_table {
+    ($($size:ident)*) => {
+        [$(rc4_apply::<rc4::consts::$size>,)*]
+    };
+}
+
+/// `rc4_apply` of keys of 1 to 256 bytes, by the length less one
+static RC4_APPLY: [Rc4Apply; 256] = rc4_apply_table!(
+    U1 U2 U3 U4 U5 U6 U7 U8 U9 U10 U11 U12 U13 U14 U15 U16
+    U17 U18 U19 U20 U21 U22 U23 U24 U25 U26 U27 U28 U29 U30 U31 U32
+    U33 U34 U35 U36 U37 U38 U39 U40 U41 U42 U43 U44 U45 U46 U47 U48
+    U49 U50 U51 U52 U53 U54 U55 U56 U57 U58 U59 U60 U61 U62 U63 U64
+    U65 U66 U67 U68 U69 U70 U71 U72 U73 U74 U75 U76 U77 U78 U79 U80
+    U81 U82 U83 U84 U85 U86 U87 U88 U89 U90 U91 U92 U93 U94 U95 U96
+    U97 U98 U99 U100 U101 U102 U103 U104 U105 U106 U107 U108 U109 U110 U111 U112
+    U113 U114 U115 U116 U117 U118 U119 U120 U121 U122 U123 U124 U125 U126 U127 U128
+    U129 U130 U131 U132 U133 U134 U135 U136 U137 U138 U139 U140 U141 U142 U143 U144
+    U145 U146 U147 U148 U149 U150 U151 U152 U153 U154 U155 U156 U157 U158 U159 U160
+    U161 U162 U163 U164 U165 U166 U167 U168 U169 U170 U171 U172 U173 U174 U175 U176
+    U177 U178 U179 U180 U181 U182 U183 U184 U185 U186 U187 U188 U189 U190 U191 U192
+    U193 U194 U195 U196 U197 U198 U199 U200 U201 U202 U203 U204 U205 U206 U207 U208
+    U209 U210 U211 U212 U213 U214 U215 U216 U217 U218 U219 U220 U221 U222 U223 U224
+    U225 U226 U227 U228 U229 U230 U231 U232 U233 U234 U235 U236 U237 U238 U239 U240
+    U241 U242 U243 U244 U245 U246 U247 U248 U249 U250 U251 U252 U253 U254 U255 U256
+);
+
+// keep keys out of logs
+impl fmt::Debug for Rc4 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Rc4")
+            .field("key_len", &self.key.len())
+            .finish()
+    }
+}
+
+impl Cipher for Rc4 {
+    fn apply_keystream(&self, data: &mut [u8]) {
+        (self.apply)(&self.key, data);
+    }
+
+    fn id(&self) -> String {
+        format!("rc4:{}", hex::encode(&self.key))
+    }
+}
+
+/// ChaCha20 of a fixed nonce, for runtimes of our own
+#[cfg(feature = "chacha20")]
+#[derive(Clone)]
+pub struct ChaCha20 {
+    key: [u8; 32],
+    nonce: [u8; 12],
+}
+
+#[cfg(feature = "chacha20")]
+impl ChaCha20 {
+    pub fn new(key: &[u8], nonce: &[u8]) -> Result<Self, io::Error> {
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidInput, what);
+        Ok(Self {
+            key: key
+                .try_into()
+                .map_err(|_| invalid("ChaCha20 key should be 32 bytes"))?,
+            nonce: nonce
+                .try_into()
+                .map_err(|_| invalid("ChaCha20 nonce should be 12 bytes"))?,
+        })
+    }
+}
+
+#[cfg(feature = "chacha20")]
+impl fmt::Debug for ChaCha20 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChaCha20").finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "chacha20")]
+impl Cipher for ChaCha20 {
+    fn apply_keystream(&self, data: &mut [u8]) {
+        use chacha20::cipher::{KeyIvInit, StreamCipher};
+
+        let mut cipher = chacha20::ChaCha20::new(&self.key.into(), &self.nonce.into());
+        cipher.apply_keystream(data);
+    }
+
+    fn id(&self) -> String {
+        format!(
+            "chacha20:{}:{}",
+            hex::encode(self.key),
+            hex::encode(self.nonce)
+        )
+    }
+}
+
+/// Ciphers of the loader and the bundled entries a runtime expects
+#[derive(Debug, Clone)]
+pub struct CryptoProfile {
+    resource: Arc<dyn Cipher>,
+    ulib: Arc<dyn Cipher>,
+}
+
+impl CryptoProfile {
+    pub fn new(resource: impl Cipher + 'static, ulib: impl Cipher + 'static) -> Self {
+        Self {
+            resource: Arc::new(resource),
+            ulib: Arc::new(ulib),
+        }
+    }
+
+    /// RC4 with the keys of the official DreamMaker runtime
+    pub fn dream_maker() -> Self {
+        DREAM_MAKER.clone()
+    }
+
+    pub fn encrypt_res(&self, plain: &mut [u8]) {
+        self.resource.apply_keystream(plain);
+    }
+
+    pub fn decrypt_res(&self, cipher: &mut [u8]) {
+        self.resource.apply_keystream(cipher);
+    }
+
+    pub fn encrypt_ulib(&self, plain: &mut [u8]) {
+        self.ulib.apply_keystream(plain);
+    }
+
+    pub fn decrypt_ulib(&self, cipher: &mut [u8]) {
+        self.ulib.apply_keystream(cipher);
+    }
+
+    pub fn id(&self) -> String {
+        format!("{}/{}", self.resource.id(), self.ulib.id())
+    }
+}
+
+impl Default for CryptoProfile {
+    fn default() -> Self {
+        Self::dream_maker()
+    }
+}
 
 pub fn encrypt_res(plain: &mut [u8]) {
-    let mut rc4 = Rc4::<U19>::new(RESOURCE_KEY.into());
-    rc4.apply_keystream(plain);
+    DREAM_MAKER.encrypt_res(plain)
 }
 
 pub fn decrypt_res(cipher: &mut [u8]) {
-    encrypt_res(cipher)
+    DREAM_MAKER.decrypt_res(cipher)
 }
 
 pub fn encrypt_ulib(plain: &mut [u8]) {
-    let mut rc4 = Rc4::<U14>::new(ULIB_KEY.into());
-    rc4.apply_keystream(plain);
+    DREAM_MAKER.encrypt_ulib(plain)
 }
 
 pub fn decrypt_ulib(cipher: &mut [u8]) {
-    encrypt_ulib(cipher)
+    DREAM_MAKER.decrypt_ulib(cipher)
 }
 
 const COMPRESS_MAGIC: u32 = 0x033E0F0D;
+const COMPRESS_HEADER_LEN: usize = 8;
 
-pub fn decompress(data: &[u8], buf: &mut Vec<u8>) -> Result<(), io::Error> {
-    let mut cursor = Cursor::new(data);
+/// Largest content `decompress` accepts, far beyond any real game
+pub const DEFAULT_LIMIT: u64 = 64 << 20;
+
+/// Most of the declared size allocated before any content is seen, the rest grows with it
+const MAX_RESERVE: u64 = 1 << 20;
+
+/// Check if `data` starts as a compressed container
+pub fn is_compressed(data: &[u8]) -> bool {
+    data.len() >= COMPRESS_HEADER_LEN && data[..4] == COMPRESS_MAGIC.to_le_bytes()
+}
+
+/// Reader of the content of a compressed container
+///
+/// Content beyond or short of the size in the header, and data after the compressed
+/// stream, are errors, reported once the content is read to its end.
+pub struct Decompressor<R> {
+    inner: ZlibDecoder<R>,
+    size: u64,
+    read: u64,
+}
+
+impl<R: BufRead> Decompressor<R> {
+    /// Read the header, rejecting containers of content larger than `limit`
+    pub fn new(mut reader: R, limit: u64) -> Result<Self, io::Error> {
+        let mut header = [0; COMPRESS_HEADER_LEN];
+        reader
+            .read_exact(&mut header)
+            .map_err(|err| match err.kind() {
+                io::ErrorKind::UnexpectedEof => {
+                    io::Error::new(io::ErrorKind::UnexpectedEof, "compressed data too short")
+                }
+                _ => err,
+            })?;
+
+        let magic = u32::from_le_bytes(header[..4].try_into().unwrap());
+        if magic != COMPRESS_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown magic {:X}", magic),
+            ));
+        }
+
+        let size = u64::from(u32::from_le_bytes(header[4..].try_into().unwrap()));
+        if size > limit {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("content of {} bytes exceeds the limit of {}", size, limit),
+            ));
+        }
+
+        Ok(Self {
+            inner: ZlibDecoder::new(reader),
+            size,
+            read: 0,
+        })
+    }
+
+    /// Size of the content declared by the header
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner.into_inner()
+    }
+}
+
+impl<R: BufRead> Read for Decompressor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // ask for one byte more than left, to tell content beyond the declared size
+        let left = usize::try_from(self.size - self.read + 1).unwrap_or(usize::MAX);
+        let len = buf.len().min(left);
+        let n = self.inner.read(&mut buf[..len])?;
+        self.read += n as u64;
+
+        if self.read > self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "content larger than declared",
+            ));
+        }
+        if n == 0 {
+            if self.read < self.size {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "compressed data truncated",
+                ));
+            }
+            if !self.inner.get_mut().fill_buf()?.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "trailing data after compressed content",
+                ));
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// Writer of a compressed container, whose content size goes into the header up front
+pub struct Compressor<W: Write> {
+    inner: ZlibEncoder<W>,
+    size: u64,
+    written: u64,
+}
+
+impl<W: Write> Compressor<W> {
+    /// Write the header of a container of `size` bytes of content
+    pub fn new(mut writer: W, size: u32) -> Result<Self, io::Error> {
+        writer.write_all(&COMPRESS_MAGIC.to_le_bytes())?;
+        writer.write_all(&size.to_le_bytes())?;
+        Ok(Self {
+            inner: ZlibEncoder::new(writer, Compression::default()),
+            size: u64::from(size),
+            written: 0,
+        })
+    }
 
-    buf.resize(8, 0);
-    cursor.read_exact(buf).unwrap();
+    /// Complete the compressed stream, which fails if less content than declared is written
+    pub fn finish(self) -> Result<W, io::Error> {
+        if self.written != self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} bytes written of {} declared", self.written, self.size),
+            ));
+        }
+        self.inner.finish()
+    }
+}
 
-    let magic = u32::from_le_bytes(buf[..4].try_into().unwrap());
-    if magic != COMPRESS_MAGIC {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            format!("{:X}", magic),
-        ));
+impl<W: Write> Write for Compressor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written + buf.len() as u64 > self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "content larger than declared",
+            ));
+        }
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
     }
 
-    let size = u32::from_le_bytes(buf[4..].try_into().unwrap());
-    buf.resize(size.try_into().unwrap(), 0);
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub fn decompress(data: &[u8], buf: &mut Vec<u8>) -> Result<(), io::Error> {
+    decompress_with_limit(data, buf, DEFAULT_LIMIT)
+}
 
-    ZlibDecoder::new(cursor).read_exact(buf)
+/// Decompress content of at most `limit` bytes into `buf`
+pub fn decompress_with_limit(data: &[u8], buf: &mut Vec<u8>, limit: u64) -> Result<(), io::Error> {
+    let mut decompressor = Decompressor::new(data, limit)?;
+    buf.clear();
+    // the declared size is by far the most likely one, but a header costs nothing to forge
+    buf.reserve(decompressor.size().min(MAX_RESERVE) as usize);
+    decompressor.read_to_end(buf)?;
+    Ok(())
 }
 
+/// Append a container of `data` to `buf`, return its length
 pub fn compress(data: &[u8], buf: &mut Vec<u8>) -> Result<usize, io::Error> {
-    let len: u32 = data.len().try_into().unwrap();
-    buf.extend(COMPRESS_MAGIC.to_le_bytes());
-    buf.extend(len.to_le_bytes());
-    ZlibEncoder::new(data, Compression::default()).read_to_end(buf)
+    let size = data
+        .len()
+        .try_into()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "data too large to compress"))?;
+    let start = buf.len();
+    let mut compressor = Compressor::new(&mut *buf, size)?;
+    compressor.write_all(data)?;
+    compressor.finish()?;
+    Ok(buf.len() - start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rc4_test_vector() {
+        // from RFC 6229, the first bytes of the keystream
+        let rc4 = Rc4::new(&[1, 2, 3, 4, 5]).unwrap();
+        let mut data = [0; 8];
+        rc4.apply_keystream(&mut data);
+        assert_eq!(data, [0xb2, 0x39, 0x63, 0x05, 0xf0, 0x3d, 0xc0, 0x27]);
+        let rc4 = Rc4::new(&(1..=16).collect::<Vec<u8>>()).unwrap();
+        let mut data = [0; 8];
+        rc4.apply_keystream(&mut data);
+        assert_eq!(data, [0x9a, 0xc7, 0xcc, 0x9a, 0x60, 0x9d, 0x1e, 0xf7]);
+
+        assert!(Rc4::new(&[0; 256]).is_ok());
+        assert!(Rc4::new(&[]).is_err());
+        assert!(Rc4::new(&[0; 257]).is_err());
+
+        let custom = CryptoProfile::new(Rc4::new(b"res").unwrap(), Rc4::new(b"ulib").unwrap());
+        let mut data = *b"__U_Lib";
+        custom.encrypt_res(&mut data);
+        assert_ne!(&data, b"__U_Lib");
+        custom.decrypt_res(&mut data);
+        assert_eq!(&data, b"__U_Lib");
+        assert_ne!(custom.id(), CryptoProfile::dream_maker().id());
+    }
+
+    #[cfg(feature = "chacha20")]
+    #[test]
+    fn chacha20_test_vector() {
+        // from RFC 8439, appendix A.1, the first block of the keystream of an all zero key
+        let chacha20 = ChaCha20::new(&[0; 32], &[0; 12]).unwrap();
+        let mut data = [0; 16];
+        chacha20.apply_keystream(&mut data);
+        assert_eq!(
+            data,
+            [
+                0x76, 0xb8, 0xe0, 0xad, 0xa0, 0xf1, 0x3d, 0x90, 0x40, 0x5d, 0x6a, 0xe5, 0x53, 0x86,
+                0xbd, 0x28
+            ]
+        );
+
+        let custom = CryptoProfile::new(
+            ChaCha20::new(&[1; 32], &[2; 12]).unwrap(),
+            ChaCha20::new(&[3; 32], &[4; 12]).unwrap(),
+        );
+        let mut data = *b"__U_Lib";
+        custom.encrypt_ulib(&mut data);
+        assert_ne!(&data, b"__U_Lib");
+        custom.decrypt_ulib(&mut data);
+        assert_eq!(&data, b"__U_Lib");
+        assert!(ChaCha20::new(&[0; 16], &[0; 12]).is_err());
+        assert!(ChaCha20::new(&[0; 32], &[0; 8]).is_err());
+    }
+
+    #[test]
+    fn reject_malformed_containers() {
+        let content = b"local a = 1\n".repeat(100);
+        let mut data = Vec::new();
+        compress(&content, &mut data).unwrap();
+
+        let mut buf = Vec::new();
+        decompress(&data, &mut buf).unwrap();
+        assert_eq!(buf, content);
+
+        let mut streamed = Vec::new();
+        let mut decompressor = Decompressor::new(&data[..], DEFAULT_LIMIT).unwrap();
+        io::copy(&mut decompressor, &mut streamed).unwrap();
+        assert_eq!(streamed, content);
+
+        let err = decompress_with_limit(&data, &mut buf, 1000).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let err = decompress(&data[..data.len() - 10], &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut trailing = data.clone();
+        trailing.push(0);
+        let err = decompress(&trailing, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // sizes in the header have to match the content both ways
+        for size in [1100, 1300] {
+            let mut forged = data.clone();
+            forged[4..8].copy_from_slice(&u32::to_le_bytes(size));
+            assert!(decompress(&forged, &mut buf).is_err());
+        }
+
+        // nor is memory for a forged size taken before the content runs out
+        let mut forged = data.clone();
+        forged[4..8].copy_from_slice(&u32::to_le_bytes(DEFAULT_LIMIT as u32));
+        let mut buf = Vec::new();
+        assert!(decompress(&forged, &mut buf).is_err());
+        assert!(buf.capacity() <= MAX_RESERVE as usize);
+
+        let mut compressor = Compressor::new(Vec::new(), 10).unwrap();
+        assert!(compressor.write_all(&content).is_err());
+        compressor.write_all(b"short").unwrap();
+        assert!(compressor.finish().is_err());
+    }
 }
@@ -0,0 +1,183 @@
+//! Responses of downloaded files, resumable by `Range` requests
+
+use std::{fmt::Write, ops::RangeInclusive};
+
+use axum::response::{IntoResponse, Response};
+use hyper::{header, HeaderMap, StatusCode};
+use sha2::{Digest, Sha256};
+
+/// Respond with `data` or the part of it `headers` ask for, to be saved as `filename`
+pub fn respond(headers: &HeaderMap, data: &[u8], filename: &str) -> Response {
+    let digest = Sha256::digest(data);
+    let etag = format!("\"{}\"", hex::encode(digest));
+
+    let mut response_headers = HeaderMap::new();
+    let mut insert = |name, value: String| {
+        // values are built from hex, base64 and percent encoded text, always valid
+        response_headers.insert(name, value.parse().unwrap());
+    };
+    insert(header::CONTENT_TYPE, "application/octet-stream".to_owned());
+    insert(header::CONTENT_DISPOSITION, content_disposition(filename));
+    insert(header::ACCEPT_RANGES, "bytes".to_owned());
+    insert(header::ETAG, etag.clone());
+    // RFC 3230 digest of the whole file, also for partial responses
+    insert(
+        header::HeaderName::from_static("digest"),
+        format!("sha-256={}", base64::encode(digest)),
+    );
+
+    // a range of a changed file would be stitched to a stale part, send all of it instead
+    let range = headers
+        .get(header::RANGE)
+        .filter(|_| match headers.get(header::IF_RANGE) {
+            Some(if_range) => if_range.as_bytes() == etag.as_bytes(),
+            None => true,
+        })
+        .and_then(|range| range.to_str().ok())
+        .and_then(|range| parse_range(range, data.len()));
+
+    match range {
+        None => (response_headers, data.to_vec()).into_response(),
+        Some(Ok(range)) => {
+            insert(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", range.start(), range.end(), data.len()),
+            );
+            (
+                StatusCode::PARTIAL_CONTENT,
+                response_headers,
+                data[range].to_vec(),
+            )
+                .into_response()
+        }
+        Some(Err(())) => {
+            insert(header::CONTENT_RANGE, format!("bytes */{}", data.len()));
+            (StatusCode::RANGE_NOT_SATISFIABLE, response_headers).into_response()
+        }
+    }
+}
+
+/// Single byte range of `Range` within `len` bytes
+///
+/// `None` for anything else, which is answered by the whole file as RFC 7233 allows.
+/// `Some(Err(()))` if the range is valid but not satisfiable.
+fn parse_range(range: &str, len: usize) -> Option<Result<RangeInclusive<usize>, ()>> {
+    let spec = range.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.trim().split_once('-')?;
+    let parse = |n: &str| n.parse::<u64>().ok();
+
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", "") => return None,
+        // last `n` bytes
+        ("", n) => {
+            let n = parse(n)?;
+            if n == 0 || len == 0 {
+                return Some(Err(()));
+            }
+            (
+                len.saturating_sub(n.try_into().unwrap_or(usize::MAX)),
+                len - 1,
+            )
+        }
+        (start, end) => {
+            let start = parse(start)?;
+            let end = match end {
+                "" => u64::MAX,
+                end => parse(end)?,
+            };
+            if end < start {
+                return None;
+            }
+            if start >= len as u64 {
+                return Some(Err(()));
+            }
+            (start as usize, end.min(len as u64 - 1) as usize)
+        }
+    };
+    Some(Ok(start..=end))
+}
+
+/// `attachment` with an ASCII fallback of `filename`, and itself by RFC 5987
+fn content_disposition(filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() || "-_.".contains(c) => c,
+            _ => '_',
+        })
+        .collect();
+
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback,
+        percent_encode(filename)
+    )
+}
+
+/// Percent encode UTF-8 bytes of `s` but ASCII letters, digits and `-_.`, for headers and paths
+pub fn percent_encode(s: &str) -> String {
+    let mut encoded = String::new();
+    for b in s.bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' => encoded.push(b as char),
+            b => write!(encoded, "%{:02X}", b).unwrap(),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranged_download() {
+        let data: Vec<u8> = (0..100).collect();
+        let get = |range: Option<&str>, if_range: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(range) = range {
+                headers.insert(header::RANGE, range.parse().unwrap());
+            }
+            if let Some(if_range) = if_range {
+                headers.insert(header::IF_RANGE, if_range.parse().unwrap());
+            }
+            respond(&headers, &data, "游戏_1.res")
+        };
+
+        let full = get(None, None);
+        assert_eq!(full.status(), StatusCode::OK);
+        let etag = full.headers()[header::ETAG].to_str().unwrap().to_owned();
+        assert_eq!(
+            full.headers()[header::CONTENT_DISPOSITION],
+            "attachment; filename=\"___1.res\"; filename*=UTF-8''%E6%B8%B8%E6%88%8F_1.res"
+        );
+
+        let partial = get(Some("bytes=90-"), Some(&etag));
+        assert_eq!(partial.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(partial.headers()[header::CONTENT_RANGE], "bytes 90-99/100");
+
+        // stale validators get the whole file
+        let stale = get(Some("bytes=90-"), Some("\"0000\""));
+        assert_eq!(stale.status(), StatusCode::OK);
+
+        let unsatisfiable = get(Some("bytes=100-"), None);
+        assert_eq!(unsatisfiable.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(
+            unsatisfiable.headers()[header::CONTENT_RANGE],
+            "bytes */100"
+        );
+
+        assert_eq!(parse_range("bytes=0-0", 100), Some(Ok(0..=0)));
+        assert_eq!(parse_range("bytes=-10", 100), Some(Ok(90..=99)));
+        assert_eq!(parse_range("bytes=-1000", 100), Some(Ok(0..=99)));
+        assert_eq!(parse_range("bytes=50-1000", 100), Some(Ok(50..=99)));
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), None);
+        assert_eq!(parse_range("bytes=5-1", 100), None);
+        assert_eq!(parse_range("items=0-1", 100), None);
+        assert_eq!(parse_range("bytes=-0", 100), Some(Err(())));
+        assert_eq!(parse_range("bytes=0-", 0), Some(Err(())));
+    }
+}
@@ -0,0 +1,87 @@
+use std::{fmt, io};
+
+/// Why a game could not be built
+#[derive(Debug)]
+pub enum Error {
+    /// Uploaded database is too small or its plugin header is malformed
+    InvalidHeader(String),
+    /// Database is made for another version of the engine
+    EngineVersion {
+        expected: u32,
+        found: u32,
+    },
+    /// Lua source failed to compile
+    Syntax {
+        chunk: String,
+        /// Line in the compiled chunk, if LuaJIT reported one
+        line: Option<usize>,
+        message: String,
+        /// Numbered source lines around `line`, only kept for the database
+        excerpt: Vec<(usize, String)>,
+    },
+    /// Strings of the database can not be encrypted, although it compiles
+    Obfuscation {
+        /// Line in the uploaded file
+        line: usize,
+    },
+    /// Any other failure of LuaJIT
+    Lua(mlua::Error),
+    /// Linked LuaJIT is not 2.0, named by its `jit.version`
+    UnsupportedLuaJit(String),
+    Compression(io::Error),
+    /// Text which can not be represented in GBK
+    Encoding(String),
+    /// Builder is used without setting a required field
+    MissingField(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidHeader(reason) => write!(f, "invalid database header: {}", reason),
+            Error::EngineVersion { expected, found } => write!(
+                f,
+                "database is made for engine version {}, expected {}",
+                found, expected
+            ),
+            Error::Syntax {
+                chunk,
+                line: Some(line),
+                message,
+                ..
+            } => write!(f, "syntax error in {} at line {}: {}", chunk, line, message),
+            Error::Syntax {
+                chunk,
+                line: None,
+                message,
+                ..
+            } => write!(f, "syntax error in {}: {}", chunk, message),
+            Error::Obfuscation { line } => {
+                write!(f, "strings at line {} can not be encrypted", line)
+            }
+            Error::Lua(err) => write!(f, "lua error: {}", err),
+            Error::UnsupportedLuaJit(name) => {
+                write!(f, "{} is linked, but games are built by LuaJIT 2.0", name)
+            }
+            Error::Compression(err) => write!(f, "compression failed: {}", err),
+            Error::Encoding(what) => write!(f, "{} can not be encoded in GBK", what),
+            Error::MissingField(field) => write!(f, "{} should be set", field),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Lua(err) => Some(err),
+            Error::Compression(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<mlua::Error> for Error {
+    fn from(err: mlua::Error) -> Self {
+        Error::Lua(err)
+    }
+}
@@ -0,0 +1,168 @@
+//! Plugin info header in front of the uploaded database
+//!
+//! Layout as DreamMaker writes it, integers are little endian:
+//! - `0x00..0x40` game name in GBK, padded with NUL
+//! - `0x40..0x44` engine version
+//! - `0x44..0x48` flags
+//! - `0x48..0x200` names of enabled plugins in GBK, each terminated by NUL,
+//!   the list ends at an empty name and the rest is padded with NUL
+
+use encoding_rs::GBK;
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+
+pub const HEADER_LEN: usize = 0x200;
+
+const NAME_LEN: usize = 0x40;
+const PLUGINS_OFFSET: usize = 0x48;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PluginHeader {
+    pub name: String,
+    pub engine_version: u32,
+    pub flags: u32,
+    pub plugins: Vec<String>,
+}
+
+impl PluginHeader {
+    /// Parse the header at the beginning of `database`
+    pub fn parse(database: &[u8]) -> Result<Self, Error> {
+        let header = database.get(..HEADER_LEN).ok_or_else(|| {
+            Error::InvalidHeader(format!(
+                "{} bytes is shorter than the plugin header",
+                database.len()
+            ))
+        })?;
+
+        let (name, _) = terminated(&header[..NAME_LEN]);
+        let name = decode(name, "game name")?;
+
+        let engine_version = u32::from_le_bytes(header[0x40..0x44].try_into().unwrap());
+        let flags = u32::from_le_bytes(header[0x44..0x48].try_into().unwrap());
+
+        let (list, _) = padded(&header[PLUGINS_OFFSET..]);
+        let plugins = list
+            .split(|b| *b == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| decode(name, "plugin name"))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            name,
+            engine_version,
+            flags,
+            plugins,
+        })
+    }
+
+    /// Fields of the header at the beginning of `database` whose padding is not all NUL, which
+    /// `parse` ignores rather than rejecting the database
+    pub fn irregularities(database: &[u8]) -> Vec<&'static str> {
+        let header = match database.get(..HEADER_LEN) {
+            Some(header) => header,
+            None => return Vec::new(),
+        };
+
+        let mut irregularities = Vec::new();
+        if !terminated(&header[..NAME_LEN]).1 {
+            irregularities.push("game name is not padded");
+        }
+        if !padded(&header[PLUGINS_OFFSET..]).1 {
+            irregularities.push("plugin list is not terminated");
+        }
+        irregularities
+    }
+}
+
+/// Content before the first NUL, and whether the padding from it is all NUL
+fn terminated(field: &[u8]) -> (&[u8], bool) {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    let (content, padding) = field.split_at(end);
+    (content, padding.iter().all(|b| *b == 0))
+}
+
+/// Content before the padding, and whether the padding is all NUL
+fn padded(field: &[u8]) -> (&[u8], bool) {
+    // the plugin list itself is NUL separated, so padding starts at two NUL in a row
+    let end = field
+        .windows(2)
+        .position(|w| w == [0, 0])
+        .unwrap_or(field.len());
+    let (content, padding) = field.split_at(end);
+    let content = content.strip_suffix(&[0]).unwrap_or(content);
+    (content, padding.iter().all(|b| *b == 0))
+}
+
+fn decode(text: &[u8], what: &str) -> Result<String, Error> {
+    let (text, had_errors) = GBK.decode_without_bom_handling(text);
+    if had_errors || text.chars().any(char::is_control) {
+        return Err(Error::InvalidHeader(format!(
+            "{} is not valid GBK text",
+            what
+        )));
+    }
+    Ok(text.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(name: &str, version: u32, plugins: &[&str]) -> Vec<u8> {
+        let mut data = vec![0; HEADER_LEN];
+        let (name, _, _) = GBK.encode(name);
+        data[..name.len()].copy_from_slice(&name);
+        data[0x40..0x44].copy_from_slice(&version.to_le_bytes());
+
+        let mut pos = PLUGINS_OFFSET;
+        for plugin in plugins {
+            let (plugin, _, _) = GBK.encode(plugin);
+            data[pos..pos + plugin.len()].copy_from_slice(&plugin);
+            pos += plugin.len() + 1;
+        }
+        data
+    }
+
+    #[test]
+    fn parse_header() {
+        let data = header("梦想世界", 3, &["战斗", "背包"]);
+        let header = PluginHeader::parse(&data).unwrap();
+        assert_eq!(header.name, "梦想世界");
+        assert_eq!(header.engine_version, 3);
+        assert_eq!(header.plugins, ["战斗", "背包"]);
+
+        // blank header of databases without plugins
+        let header = PluginHeader::parse(&[0; HEADER_LEN]).unwrap();
+        assert_eq!(header.name, "");
+        assert!(header.plugins.is_empty());
+
+        assert!(PluginHeader::irregularities(&data).is_empty());
+
+        // anything after the padding starts is left out
+        let mut data = data;
+        data[0x30] = b'x';
+        data[0x1f0] = b'y';
+        let header = PluginHeader::parse(&data).unwrap();
+        assert_eq!(header.name, "梦想世界");
+        assert_eq!(header.plugins, ["战斗", "背包"]);
+        assert_eq!(
+            PluginHeader::irregularities(&data),
+            ["game name is not padded", "plugin list is not terminated"]
+        );
+
+        // the name ends at its first NUL, even if text follows
+        let mut named = vec![0; HEADER_LEN];
+        named[..7].copy_from_slice(b"abc\0x\0\0");
+        assert_eq!(PluginHeader::parse(&named).unwrap().name, "abc");
+        assert_eq!(
+            PluginHeader::irregularities(&named),
+            ["game name is not padded"]
+        );
+
+        assert!(matches!(
+            PluginHeader::parse(&data[..0x100]),
+            Err(Error::InvalidHeader(_))
+        ));
+    }
+}
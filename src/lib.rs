@@ -1,20 +1,111 @@
-use bundle::Bundles;
 use encoding_rs::GBK;
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, str::FromStr};
 use time::{format_description, PrimitiveDateTime};
 
+use luagen::Template;
+
 pub mod crypto;
 
 mod lua;
 
 mod bundle;
 
+mod bytecode;
+
+mod error;
+
+mod header;
+
+mod library;
+
+mod lint;
+
+mod luagen;
+
+mod obfuscate;
+
+pub use bundle::Bundles;
+pub use error::Error;
+pub use header::PluginHeader;
+pub use library::Libraries;
+pub use lint::{Linter, Warning, WarningKind};
+
+const ADAPTOR: Template = Template::new(include_str!("../static/adaptor/adaptor.lua"));
+const ADAPTOR_LOGIN: Template = Template::new(include_str!("../static/adaptor/login.lua"));
+const ADAPTOR_UPDATE: Template = Template::new(include_str!("../static/adaptor/update.lua"));
+const ADAPTOR_CHEAT: Template = Template::new(include_str!("../static/adaptor/cheat.lua"));
+const ADAPTOR_SPEED: &str = include_str!("../static/adaptor/speed.lua");
+const ADAPTOR_MEMORY: &str = include_str!("../static/adaptor/memory.lua");
+
+/// Global function of games protected from memory cheats, keeping fields of a table protected
+/// as `保护数据(表, 键, ...)`
+pub const PROTECT_FUNCTION: &str = "保护数据";
+
+/// Check that the linked LuaJIT is 2.0, the only version whose bytecode the engine loads
+///
+/// LuaJIT 2.1 links all the same and compiles games, which then neither load nor are read back.
+pub fn check_luajit() -> Result<(), Error> {
+    lua::check_dump_version(bytecode::VERSION)
+}
+
+/// What games do once they detect a cheat
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheatResponse {
+    /// Undo the cheat and keep running, speed hacks are slowed down to real time and changed
+    /// values restored
+    #[default]
+    Correct,
+    /// Raise a Lua error, which the runtime reports as any other script error, once a cheat is
+    /// detected, and undo the cheat from then on
+    Error,
+    /// Quit the game
+    Exit,
+}
+
+impl CheatResponse {
+    fn as_str(self) -> &'static str {
+        match self {
+            CheatResponse::Correct => "correct",
+            CheatResponse::Error => "error",
+            CheatResponse::Exit => "exit",
+        }
+    }
+}
+
+impl FromStr for CheatResponse {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "correct" => Ok(CheatResponse::Correct),
+            "error" => Ok(CheatResponse::Error),
+            "exit" => Ok(CheatResponse::Exit),
+            _ => Err(format!("unknown cheat response {s}")),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct GameRes<'a, 'b, 'c> {
     keywords: Option<&'a str>,
     database: Option<&'b [u8]>,
     statistics: bool,
+    statistics_report: Option<(&'c str, u32, u32)>,
+    speed_hack: bool,
+    memory_cheat: bool,
+    driver: bool,
+    cheat_response: CheatResponse,
     build_time: Option<PrimitiveDateTime>,
     filename: Option<&'c str>,
+    login_server: Option<(&'c str, u16)>,
+    update: Option<(&'c str, u32)>,
+    engine_version: Option<u32>,
+    libraries: Option<&'c Libraries>,
+    strip: bool,
+    obfuscate: bool,
+    compiled: Option<&'b [u8]>,
 }
 
 impl<'a, 'b, 'c> GameRes<'a, 'b, 'c> {
@@ -29,8 +120,10 @@ impl<'a, 'b, 'c> GameRes<'a, 'b, 'c> {
         self
     }
 
-    pub fn anti_memory_cheat(self, switch: bool) -> Self {
-        assert!(switch);
+    /// Keep gold, HP and experience of the player, and fields the game passes to
+    /// [`PROTECT_FUNCTION`], obfuscated and checked against changes
+    pub fn anti_memory_cheat(mut self, switch: bool) -> Self {
+        self.memory_cheat = switch;
         self
     }
 
@@ -39,6 +132,13 @@ impl<'a, 'b, 'c> GameRes<'a, 'b, 'c> {
         self
     }
 
+    /// Request `url` as the game starts, and give the runtime `gid` of user `uid` instead of
+    /// placeholder ids
+    pub fn statistics_report(mut self, url: &'c str, gid: u32, uid: u32) -> Self {
+        self.statistics_report = Some((url, gid, uid));
+        self
+    }
+
     pub fn build_time(mut self, time: PrimitiveDateTime) -> Self {
         self.build_time = Some(time);
         self
@@ -49,8 +149,63 @@ impl<'a, 'b, 'c> GameRes<'a, 'b, 'c> {
         self
     }
 
-    pub fn anti_speed_hack(self, switch: bool) -> Self {
-        assert!(switch);
+    /// Detect the runtime ticking faster than the wall clock
+    pub fn anti_speed_hack(mut self, switch: bool) -> Self {
+        self.speed_hack = switch;
+        self
+    }
+
+    /// Build for runtimes with their driver (`驱动`) enabled, or for ones without it if `false`
+    ///
+    /// Sys.lua quits games started by a runtime of the other kind.
+    pub fn driver(mut self, switch: bool) -> Self {
+        self.driver = switch;
+        self
+    }
+
+    /// Respond to cheats detected by the enabled protections with `response`, games undo them by
+    /// default
+    pub fn cheat_response(mut self, response: CheatResponse) -> Self {
+        self.cheat_response = response;
+        self
+    }
+
+    /// Build an online game which asks `host:port` for `/login` with the account of the player
+    /// as it starts, and quits unless the answer is `1`
+    pub fn login_server(mut self, host: &'c str, port: u16) -> Self {
+        self.login_server = Some((host, port));
+        self
+    }
+
+    /// Build a game which asks `url` for its manifest as it starts, and calls the global
+    /// `发现新版本(ver, url)` of the game if resources newer than version `ver` are there
+    pub fn auto_update(mut self, url: &'c str, ver: u32) -> Self {
+        self.update = Some((url, ver));
+        self
+    }
+
+    /// Reject databases made for another version of the engine
+    pub fn engine_version(mut self, ver: u32) -> Self {
+        self.engine_version = Some(ver);
+        self
+    }
+
+    /// Bundle `libraries` instead of the builtin ones
+    pub fn libraries(mut self, libraries: &'c Libraries) -> Self {
+        self.libraries = Some(libraries);
+        self
+    }
+
+    /// Strip line numbers and chunk names from the compiled code, builds of the same
+    /// inputs and build time are then byte-identical
+    pub fn strip_debug_info(mut self, switch: bool) -> Self {
+        self.strip = switch;
+        self
+    }
+
+    /// Encrypt string constants of the database, and rename its locals if debug info is kept
+    pub fn obfuscate(mut self, switch: bool) -> Self {
+        self.obfuscate = switch;
         self
     }
 
@@ -59,63 +214,199 @@ impl<'a, 'b, 'c> GameRes<'a, 'b, 'c> {
         self
     }
 
-    fn create_adaptor(&self) -> Vec<u8> {
+    /// Bytecode `compile_database` made of the database, reused unless the build strips or
+    /// obfuscates it
+    pub fn compiled_database(mut self, chunk: &'b [u8]) -> Self {
+        self.compiled = Some(chunk);
+        self
+    }
+
+    fn create_adaptor(&self) -> Result<Vec<u8>, Error> {
         let time_fmt =
             format_description::parse("[year]-[month]-[day] [hour]:[minute]:[second]").unwrap();
 
-        let time = self.build_time.unwrap().format(&time_fmt).unwrap();
+        let build_time = self.build_time.ok_or(Error::MissingField("build_time"))?;
+        let time = build_time.format(&time_fmt).unwrap();
+        let filename = self.filename.ok_or(Error::MissingField("filename"))?;
 
-        let s = format!(
-            r#"
-        local f1 = 核心.数据统计
-        核心.数据统计 = function(gid, uid, unk, hash, time)
-            if {enable_statistics} then
-                f1({gid}, {uid}, 0, "{hash}", "{time}")
-            end
-        end
-        local f2 = 核心.anti_hacking
-        核心.anti_hacking = function(enabled, keywords)
-            f2(1, "{keywords}")
-        end
-        "#,
-            enable_statistics = self.statistics,
-            uid = 1,
-            gid = 999,
-            hash = self.filename.unwrap(),
-            time = time,
-            keywords = self.keywords.unwrap_or_default()
-        );
+        // games request the url themselves, the runtime only gets the ids
+        let (report_url, gid, uid) = match self.statistics_report {
+            Some((url, gid, uid)) => (luagen::gbk_string(url, "statistics url")?, gid, uid),
+            None => (luagen::nil(), 999, 1),
+        };
 
-        let (b, _, _) = GBK.encode(&s);
-        b.into_owned()
+        let mut s = ADAPTOR.render(&[
+            ("statistics", &luagen::boolean(self.statistics)),
+            ("gid", &luagen::integer(gid)),
+            ("uid", &luagen::integer(uid)),
+            ("report_url", &report_url),
+            ("qudong", &luagen::boolean(self.driver)),
+            ("hash", &luagen::gbk_string(filename, "filename")?),
+            ("time", &luagen::string(time.as_bytes())),
+            (
+                "keywords",
+                &luagen::gbk_string(self.keywords.unwrap_or_default(), "illegal keywords")?,
+            ),
+        ]);
+
+        if let Some((host, port)) = self.login_server {
+            s.push_str(&ADAPTOR_LOGIN.render(&[
+                ("host", &luagen::gbk_string(host, "login server")?),
+                ("port", &luagen::integer(port)),
+            ]));
+        }
+
+        if let Some((url, ver)) = self.update {
+            s.push_str(&ADAPTOR_UPDATE.render(&[
+                ("url", &luagen::gbk_string(url, "update url")?),
+                ("ver", &luagen::integer(ver)),
+            ]));
+        }
+
+        if self.speed_hack || self.memory_cheat {
+            s.push_str(&ADAPTOR_CHEAT.render(&[(
+                "response",
+                &luagen::string(self.cheat_response.as_str().as_bytes()),
+            )]));
+        }
+        if self.speed_hack {
+            s.push_str(ADAPTOR_SPEED);
+        }
+        if self.memory_cheat {
+            s.push_str(ADAPTOR_MEMORY);
+        }
+
+        let (b, _, had_errors) = GBK.encode(&s);
+        if had_errors {
+            return Err(Error::Encoding("adaptor".to_owned()));
+        }
+        Ok(b.into_owned())
+    }
+
+    pub fn build(&self) -> Result<Vec<u8>, Error> {
+        self.bundles()?.pack()
     }
 
-    pub fn build(&self) -> Result<Vec<u8>, mlua::Error> {
-        let database = self.database.expect("database should set");
-        // check if database too small
-        if database.len() < 0x200 {
-            return Err(mlua::Error::external("invalid database"));
+    /// Compile the game into bundled entries without packing them
+    pub fn bundles(&self) -> Result<Bundles, Error> {
+        let database = self.database.ok_or(Error::MissingField("database"))?;
+        let header = PluginHeader::parse(database)?;
+        match self.engine_version {
+            Some(expected) if expected != header.engine_version => {
+                return Err(Error::EngineVersion {
+                    expected,
+                    found: header.engine_version,
+                })
+            }
+            _ => {}
         }
 
         // trim plugin info header
-        let (_header, database) = database.split_at(0x200);
+        let (header, database) = database.split_at(header::HEADER_LEN);
 
-        // compile database to bytecode
-        let database = lua::compile("database.lua", database)?;
+        // compile database to bytecode, unless it is compiled as the build would
+        let database = match self.compiled {
+            Some(chunk) if !self.strip && !self.obfuscate => chunk.to_owned(),
+            _ => {
+                // obfuscation keeps lines, so errors are still located in the original source
+                let source = if self.obfuscate {
+                    match obfuscate::encrypt_strings(database) {
+                        Ok(source) => Cow::Owned(source),
+                        Err(line) => {
+                            // strings are most likely broken, which the compiler tells better
+                            lua::compile("database.lua", database, true)
+                                .map_err(|err| locate_in_upload(err, header, database))?;
+                            return Err(Error::Obfuscation {
+                                line: line + header_lines(header),
+                            });
+                        }
+                    }
+                } else {
+                    Cow::Borrowed(database)
+                };
+
+                let mut database = lua::compile("database.lua", &source, self.strip)
+                    .map_err(|err| locate_in_upload(err, header, database))?;
+                if self.obfuscate && !self.strip {
+                    obfuscate::rename_locals(&mut database)
+                        .map_err(|err| Error::Lua(mlua::Error::external(err)))?;
+                }
+                database
+            }
+        };
 
         // insert bundled library adaptor
-        let adaptor = self.create_adaptor();
+        let adaptor = self.create_adaptor()?;
 
-        let adaptor = lua::compile("adaptor.lua", adaptor)?;
+        let adaptor = lua::compile("adaptor.lua", adaptor, self.strip)?;
         // build bundles
-        let mut bundles = Bundles::with_adaptor(adaptor);
+        let mut bundles = match self.libraries {
+            Some(libraries) => Bundles::with_libraries(adaptor, libraries),
+            None => Bundles::with_adaptor(adaptor),
+        };
         bundles.set_database(database);
-        let packed = bundles.pack()?;
+        bundles.strip_debug_info(self.strip);
 
-        Ok(packed)
+        Ok(bundles)
     }
 }
 
+/// Compile the source after the plugin header of `database` with its debug info, as lint reads it
+pub fn compile_database(database: &[u8]) -> Result<Vec<u8>, Error> {
+    PluginHeader::parse(database)?;
+    let (header, source) = database.split_at(header::HEADER_LEN);
+    lua::compile("database.lua", source, false).map_err(|err| locate_in_upload(err, header, source))
+}
+
+/// Lines the plugin header takes, as it may happen to contain line breaks
+fn header_lines(header: &[u8]) -> usize {
+    header.iter().filter(|b| **b == b'\n').count()
+}
+
+/// Relate a syntax error of the header-trimmed database to lines of the uploaded file
+fn locate_in_upload(err: Error, header: &[u8], source: &[u8]) -> Error {
+    match err {
+        Error::Syntax {
+            chunk,
+            line: Some(line),
+            message,
+            ..
+        } => {
+            let offset = header_lines(header);
+
+            let excerpt = source
+                .split(|b| *b == b'\n')
+                .enumerate()
+                .skip(line.saturating_sub(2))
+                .take(3)
+                .map(|(idx, text)| {
+                    let text = text.strip_suffix(b"\r").unwrap_or(text);
+                    let (text, _) = GBK.decode_without_bom_handling(text);
+                    (idx + 1 + offset, text.into_owned())
+                })
+                .collect();
+
+            Error::Syntax {
+                chunk,
+                line: Some(line + offset),
+                message,
+                excerpt,
+            }
+        }
+        err => err,
+    }
+}
+
+/// Whether tests reading built games are able to run, as only bytecode of LuaJIT 2.0 is read
+#[cfg(test)]
+fn luajit_2_0() -> bool {
+    let checked = check_luajit();
+    if let Err(err) = &checked {
+        eprintln!("skipped: {}", err);
+    }
+    checked.is_ok()
+}
+
 #[cfg(test)]
 mod tests {
     use indexmap::IndexMap;
@@ -123,10 +414,600 @@ mod tests {
 
     use super::*;
 
+    use bstr::ByteSlice;
+    use time::{Date, Month, Time};
+
+    fn database(source: &str) -> Vec<u8> {
+        let mut data = vec![0; 0x200];
+        data.extend_from_slice(source.as_bytes());
+        data
+    }
+
+    fn build_time() -> PrimitiveDateTime {
+        PrimitiveDateTime::new(
+            Date::from_calendar_date(2022, Month::July, 1).unwrap(),
+            Time::MIDNIGHT,
+        )
+    }
+
+    #[test]
+    fn unpack_without_running_loader() {
+        if !luajit_2_0() {
+            return;
+        }
+        let database = database("local a = 1");
+        let packed = GameRes::new()
+            .build_time(build_time())
+            .filename("123")
+            .game_lua(&database)
+            .build()
+            .unwrap();
+
+        let unpacked = Bundles::unpack(&packed).unwrap();
+        let loaded = run_loader(&packed);
+        assert_eq!(unpacked.iter().count(), loaded.len());
+        for ((name, lua), (expected_name, expected_lua)) in unpacked.iter().zip(&loaded) {
+            assert_eq!(name, expected_name);
+            assert_eq!(lua, expected_lua.as_slice());
+        }
+    }
+
+    #[test]
+    fn report_syntax_error() {
+        let database = database("local a = 1\nlocal = 2");
+        let err = GameRes::new()
+            .build_time(build_time())
+            .filename("123")
+            .game_lua(&database)
+            .build()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Syntax { ref chunk, line: Some(2), .. } if chunk == "database.lua"
+        ));
+
+        // lines are counted from the uploaded file, including the header
+        let mut database = database;
+        database[0x44] = b'\n';
+        match GameRes::new()
+            .build_time(build_time())
+            .filename("123")
+            .game_lua(&database)
+            .build()
+        {
+            Err(Error::Syntax {
+                line: Some(3),
+                excerpt,
+                ..
+            }) => assert_eq!(
+                excerpt,
+                [(2, "local a = 1".to_owned()), (3, "local = 2".to_owned())]
+            ),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let database = self::database("local a = 1");
+        let err = GameRes::new().game_lua(&database).build().unwrap_err();
+        assert!(matches!(err, Error::MissingField("build_time")));
+
+        let err = GameRes::new().game_lua(&[0; 0x10]).build().unwrap_err();
+        assert!(matches!(err, Error::InvalidHeader(_)));
+    }
+
+    #[test]
+    fn reuse_compiled_database() {
+        let database = database("local a = 1");
+        let chunk = compile_database(&database).unwrap();
+        let game = GameRes::new()
+            .build_time(build_time())
+            .filename("123")
+            .game_lua(&database)
+            .compiled_database(&chunk);
+
+        let bundles = game.clone().bundles().unwrap();
+        assert_eq!(bundles.get("database.lua"), Some(&chunk[..]));
+        // builds without debug info compile it again
+        let bundles = game.strip_debug_info(true).bundles().unwrap();
+        assert_ne!(bundles.get("database.lua"), Some(&chunk[..]));
+
+        assert!(matches!(
+            compile_database(&database[..0x100]),
+            Err(Error::InvalidHeader(_))
+        ));
+    }
+
+    #[test]
+    fn obfuscate_database() {
+        let game = GameRes::new()
+            .build_time(build_time())
+            .filename("123")
+            .strip_debug_info(true)
+            .obfuscate(true);
+
+        let secret = database("return 'secret'");
+        let bundles = game.clone().game_lua(&secret).bundles().unwrap();
+        let chunk = bundles.get("database.lua").unwrap();
+        assert!(chunk.find(b"secret").is_none());
+        let lua = Lua::new();
+        assert_eq!(lua.load(chunk).eval::<String>().unwrap(), "secret");
+
+        // strings it fails to encrypt fail the build rather than being left as they are
+        let escaped = database("local a = 1\nreturn '\\u{48}'");
+        let err = game.game_lua(&escaped).build().unwrap_err();
+        // which LuaJIT 2.0 rejects itself
+        assert!(
+            matches!(
+                err,
+                Error::Obfuscation { line: 2 } | Error::Syntax { line: Some(2), .. }
+            ),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn build_login_game() {
+        if !luajit_2_0() {
+            return;
+        }
+        let database = database("local a = 1");
+        let game = GameRes::new()
+            .build_time(build_time())
+            .filename("123")
+            .game_lua(&database);
+
+        let offline = Bundles::unpack(&game.clone().build().unwrap()).unwrap();
+        assert!(offline.get("adaptor.lua").unwrap().find("/login").is_none());
+
+        let login = game.login_server("10.0.0.1", 6000).build().unwrap();
+        let login = Bundles::unpack(&login).unwrap();
+        let adaptor = login.get("adaptor.lua").unwrap();
+        assert!(adaptor.find("/login").is_some());
+        assert!(adaptor.find("10.0.0.1").is_some());
+        assert_eq!(login.iter().count(), offline.iter().count());
+    }
+
+    #[test]
+    fn check_login_account() {
+        let database = database("local a = 1");
+        let game = GameRes::new()
+            .build_time(build_time())
+            .filename("123")
+            .game_lua(&database)
+            .login_server("10.0.0.1", 6000);
+        // Sys.lua sets up 核心.读取网址 and Sys, then takes the account as the game starts
+        let start = "核心.读取网址 = function(url, callback) requested, answer = url, callback end
+            Sys = { op_qudong = true, op_login = 3 }
+            核心.anti_hacking(1, '')
+            local uname, uuid, uid, gid = 核心.get_info()
+            return Sys.op_login, uname, uuid, uid, gid, requested";
+
+        let lua = load_adaptor(&game);
+        let (login, uname, uuid, uid, gid, requested): (u8, String, String, u32, u32, String) =
+            lua.load(&*GBK.encode(start).0).eval().unwrap();
+        assert_eq!(login, 1);
+        assert_eq!((uname.as_str(), uid, gid), ("a player", 7, 8));
+        assert_eq!(uuid.len(), 24);
+        assert_eq!(
+            requested,
+            format!("http://10.0.0.1:6000/login?uname=a%20player&uuid={}", uuid)
+        );
+
+        let answer = |text: &str| {
+            lua.load(&format!("exited = false answer({:?}) return exited", text))
+                .eval::<bool>()
+                .unwrap()
+        };
+        assert!(!answer("1"));
+        assert!(answer("0"));
+        assert!(answer("-1"));
+
+        // other games take the account as it is
+        let lua = load_adaptor(&GameRes {
+            login_server: None,
+            ..game
+        });
+        let (login, _, _, _, _, requested): (u8, String, String, u32, u32, Option<String>) =
+            lua.load(&*GBK.encode(start).0).eval().unwrap();
+        assert_eq!(login, 3);
+        assert_eq!(requested, None);
+    }
+
+    #[test]
+    fn check_update_manifest() {
+        let database = database("local a = 1");
+        let game = GameRes::new()
+            .build_time(build_time())
+            .filename("123")
+            .game_lua(&database)
+            .auto_update("http://10.0.0.1/dmupdate/1/123", 2);
+        let start = "核心.读取网址 = function(url, callback) requested, answer = url, callback end
+            function 发现新版本(ver, url) found = { ver, url } end
+            Sys = { op_qudong = true, op_login = 3 }
+            核心.anti_hacking(1, '')
+            return Sys.op_login, requested";
+        let found = |lua: &Lua, manifest: &str| {
+            lua.load(&format!(
+                "found = nil answer({:?}) return found and found[1], found and found[2]",
+                manifest
+            ))
+            .eval::<(Option<u32>, Option<String>)>()
+            .unwrap()
+        };
+
+        let lua = load_adaptor(&game);
+        let (login, requested): (u8, String) = lua.load(&*GBK.encode(start).0).eval().unwrap();
+        assert_eq!(login, 2);
+        assert_eq!(requested, "http://10.0.0.1/dmupdate/1/123/manifest");
+        assert_eq!(
+            found(&lua, r#"{"filename":"123","ver":3,"files":[]}"#),
+            (
+                Some(3),
+                Some("http://10.0.0.1/dmupdate/1/123/3/".to_owned())
+            )
+        );
+        assert_eq!(
+            found(&lua, r#"{"filename":"123","ver":2,"files":[]}"#),
+            (None, None)
+        );
+        assert_eq!(found(&lua, "Not Found"), (None, None));
+    }
+
+    #[test]
+    fn report_launches() {
+        let database = database("local a = 1");
+        let url = "http://10.0.0.1/dmstat/report?gid=5&uid=1&token=t";
+        let game = GameRes::new()
+            .build_time(build_time())
+            .filename("123")
+            .game_lua(&database)
+            .statistics(true)
+            .statistics_report(url, 5, 1);
+        // Sys.lua reports right after setting up 核心.读取网址
+        let start = "核心.读取网址 = function(url, callback) requested = url end
+            核心.数据统计(999, 1, 0, '', '')
+            return counted and counted[1], counted and counted[2], counted and #counted, requested";
+        type Reported = (Option<u32>, Option<u32>, Option<usize>, Option<String>);
+
+        let lua = load_adaptor(&game);
+        let reported: Reported = lua.load(&*GBK.encode(start).0).eval().unwrap();
+        assert_eq!(reported, (Some(5), Some(1), Some(5), Some(url.to_owned())));
+
+        let lua = load_adaptor(&GameRes {
+            statistics_report: None,
+            ..game.clone()
+        });
+        let reported: Reported = lua.load(&*GBK.encode(start).0).eval().unwrap();
+        assert_eq!(reported, (Some(999), Some(1), Some(5), None));
+
+        let lua = load_adaptor(&game.statistics(false));
+        let reported: Reported = lua.load(&*GBK.encode(start).0).eval().unwrap();
+        assert_eq!(reported, (None, None, None, None));
+    }
+
+    #[test]
+    fn escape_hostile_keywords() {
+        let database = database("local a = 1");
+        let keywords = [
+            "外挂|加速",
+            "\"); os.exit(1) --",
+            "]]\n\r\0\\",
+            // second byte of `\x95\x5c` in GBK is a backslash
+            "昞\"",
+        ];
+        for keywords in keywords {
+            let game = GameRes::new()
+                .build_time(build_time())
+                .filename("123")
+                .game_lua(&database)
+                .illegal_keywords(keywords)
+                .login_server("\"..", 6000);
+            game.build().unwrap();
+            let bundles = game.bundles().unwrap();
+
+            // run the adaptor against a runtime recording what it gets
+            let lua = Lua::new();
+            let core = lua.create_table().unwrap();
+            let record = lua
+                .create_function(|lua, (_, keywords): (i64, mlua::String)| {
+                    lua.globals().set("recorded", keywords)
+                })
+                .unwrap();
+            core.set("anti_hacking", record).unwrap();
+            let account = lua.create_function(|_, ()| Ok(("a", "b"))).unwrap();
+            core.set("get_info", account).unwrap();
+            let core_name = lua.create_string(&GBK.encode("核心").0).unwrap();
+            lua.globals().set(core_name, core.clone()).unwrap();
+
+            let adaptor = bundles.get("adaptor.lua").unwrap();
+            lua.load(adaptor).exec().unwrap();
+            let hook: mlua::Function = core.get("anti_hacking").unwrap();
+            hook.call::<_, ()>((0, "")).unwrap();
+
+            let recorded: mlua::String = lua.globals().get("recorded").unwrap();
+            assert_eq!(recorded.as_bytes(), &*GBK.encode(keywords).0);
+
+            let request = lua
+                .create_function(|lua, url: String| lua.globals().set("requested", url))
+                .unwrap();
+            let request_name = lua.create_string(&GBK.encode("读取网址").0).unwrap();
+            core.set(request_name, request).unwrap();
+            let hook: mlua::Function = core.get("get_info").unwrap();
+            hook.call::<_, ()>(()).unwrap();
+            let requested: String = lua.globals().get("requested").unwrap();
+            assert_eq!(requested, "http://\"..:6000/login?uname=a&uuid=b");
+        }
+    }
+
+    /// Run the adaptor of `game` against a fake runtime, whose clocks are the globals `tick`
+    /// and `now`, and which sets `exited` instead of quitting
+    fn load_adaptor(game: &GameRes) -> Lua {
+        // debug library to get at what the adaptor keeps in upvalues
+        let lua = unsafe { Lua::unsafe_new() };
+        let runtime = "核心 = {
+                anti_hacking = function() end,
+                get_info = function() return 'a player', string.rep('0', 24), 7, 8 end,
+                数据统计 = function(...) counted = { ... } end,
+            }
+            引擎 = { 取运行时间 = function() return tick end }
+            tick, now = 0, 0
+            os.time = function() return now end
+            os.exit = function() exited = true end";
+        lua.load(&*GBK.encode(runtime).0).exec().unwrap();
+
+        let bundles = game.bundles().unwrap();
+        lua.load(bundles.get("adaptor.lua").unwrap())
+            .exec()
+            .unwrap();
+        lua
+    }
+
     #[test]
-    fn extract_bundle() {
-        let mut chunk = std::fs::read("").unwrap();
-        chunk.truncate(chunk.len() - 10);
+    fn detect_speed_hack() {
+        let database = database("local a = 1");
+        let game = GameRes::new()
+            .build_time(build_time())
+            .filename("123")
+            .game_lua(&database)
+            .anti_speed_hack(true);
+        let ticks = |lua: &Lua, tick: i64, now: i64| {
+            lua.globals().set("tick", tick).unwrap();
+            lua.globals().set("now", now).unwrap();
+            lua.load(&*GBK.encode("return 引擎.取运行时间()").0)
+                .eval::<i64>()
+        };
+
+        // real time passes as the runtime says, within a second of os.time
+        let lua = load_adaptor(&game);
+        assert_eq!(ticks(&lua, 3_990, 3).unwrap(), 3_990);
+        assert_eq!(ticks(&lua, 10_000, 10).unwrap(), 10_000);
+        assert_eq!(ticks(&lua, 12_000, 11).unwrap(), 12_000);
+
+        // ticks three times as fast are slowed down once noticed within 3 seconds
+        let lua = load_adaptor(&game);
+        for (tick, now) in [(3_000, 1), (6_000, 2), (9_000, 3)] {
+            assert_eq!(ticks(&lua, tick, now).unwrap(), tick);
+        }
+        assert_eq!(ticks(&lua, 12_000, 4).unwrap(), 10_000);
+        assert_eq!(ticks(&lua, 15_000, 5).unwrap(), 11_000);
+        // and run at full speed again once the last seconds are without the hack
+        for (tick, now) in [(16_000, 6), (17_000, 7), (18_000, 8), (19_000, 9)] {
+            ticks(&lua, tick, now).unwrap();
+        }
+        let tick = ticks(&lua, 20_000, 10).unwrap();
+        assert_eq!(ticks(&lua, 21_000, 11).unwrap(), tick + 1_000);
+
+        // errors are raised once, and the hack slowed down after
+        let lua = load_adaptor(&game.clone().cheat_response(CheatResponse::Error));
+        for (tick, now) in [(3_000, 1), (6_000, 2)] {
+            ticks(&lua, tick, now).unwrap();
+        }
+        assert!(ticks(&lua, 9_000, 3).is_err());
+        assert_eq!(ticks(&lua, 12_000, 4).unwrap(), 10_000);
+        assert_eq!(ticks(&lua, 15_000, 5).unwrap(), 11_000);
+
+        let lua = load_adaptor(&game.clone().anti_speed_hack(false));
+        assert_eq!(ticks(&lua, 30_000, 10).unwrap(), 30_000);
+    }
+
+    #[test]
+    fn protect_player_values() {
+        let database = database("local a = 1");
+        let game = GameRes::new()
+            .build_time(build_time())
+            .filename("123")
+            .game_lua(&database)
+            .anti_memory_cheat(true);
+        let eval = |lua: &Lua, source: &str| lua.load(&*GBK.encode(source).0).eval::<bool>();
+        // the hero class as 类_主角.lua registers it, and the copies kept in place of its gold
+        let player = "Sys = { add = function(name, class) hero = class end }
+            核心.anti_hacking(1, '')
+            local class = {}
+            class.__index = class
+            function class:初始化()
+                self.属性 = { 金币 = 100, hp = 50, 经验值 = 2 ^ 40 + 3, 等级 = 1 }
+            end
+            Sys.add('hero', class)
+            local player = setmetatable({}, hero)
+            player:初始化()
+            属性 = player.属性
+            local index = getmetatable(属性).__index
+            for i = 1, math.huge do
+                local name, value = debug.getupvalue(index, i)
+                if name == 'protected' then
+                    slot = value.金币
+                    return true
+                end
+            end";
+
+        let lua = load_adaptor(&game);
+        assert!(eval(&lua, player).unwrap());
+        let checks = [
+            "return rawget(属性, '金币') == nil and 属性.金币 == 100 and 属性.hp == 50
+                and 属性.经验值 == 2 ^ 40 + 3 and 属性.等级 == 1",
+            // neither copy holds the plain value
+            "for _, copy in ipairs({ slot.value, slot.shadow }) do
+                for _, v in pairs(copy) do
+                    if v == 100 then return false end
+                end
+            end
+            return true",
+            "属性.金币 = 属性.金币 + 0.5
+            属性.hp = -7
+            属性.经验值 = nil
+            return 属性.金币 == 100.5 and 属性.hp == -7 and 属性.经验值 == nil",
+            "local fields = {}
+            for k, v in pairs(属性) do fields[k] = v end
+            return fields.金币 == 100.5 and fields.hp == -7 and fields.等级 == 1",
+            // next sees the fields left in the table only, and stays the builtin
+            "local fields = {}
+            for k, v in next, 属性 do fields[k] = v end
+            return fields.金币 == nil and fields.等级 == 1
+                and tostring(next):find('builtin') ~= nil
+                and tostring(rawget):find('builtin') ~= nil",
+            // fields set by rawset are no longer protected, and listed once
+            "rawset(属性, 'hp', 3)
+            local count = 0
+            for k, v in pairs(属性) do
+                if k == 'hp' then count = count + 1 end
+            end
+            return 属性.hp == 3 and count == 1",
+            // changes behind its back are undone
+            "slot.value = { plain = 999999 }
+            return 属性.金币 == 100.5 and 属性.金币 == 100.5",
+            // and values changed in both copies are reset
+            "slot.value, slot.shadow = { plain = 999999 }, { plain = 999999 }
+            return 属性.金币 == 0 and 属性.金币 == 0",
+        ];
+        for check in checks {
+            assert!(eval(&lua, check).unwrap(), "{}", check);
+        }
+
+        let lua = load_adaptor(&game.clone().cheat_response(CheatResponse::Error));
+        assert!(eval(&lua, player).unwrap());
+        assert!(eval(&lua, "slot.shadow = { plain = 1 } return 属性.金币 == 100").is_err());
+        assert!(eval(&lua, "return 属性.金币 == 100").unwrap());
+
+        // fields of unprotected games have no metatable to look into
+        let lua = load_adaptor(&game.anti_memory_cheat(false));
+        assert!(eval(&lua, player).is_err());
+    }
+
+    #[test]
+    fn apply_sys_options() {
+        let database = database("local a = 1");
+        let game = GameRes::new()
+            .build_time(build_time())
+            .filename("123")
+            .game_lua(&database);
+        // Sys as System() of Sys.lua sets it up
+        let start = "Sys = { op_qudong = true, op_login = 3 }
+            核心.anti_hacking(1, '')
+            return Sys.op_qudong";
+
+        for driver in [true, false] {
+            let lua = load_adaptor(&game.clone().driver(driver));
+            let qudong: bool = lua.load(&*GBK.encode(start).0).eval().unwrap();
+            assert_eq!(qudong, driver);
+        }
+    }
+
+    #[test]
+    fn reproducible_build() {
+        if !luajit_2_0() {
+            return;
+        }
+        let database = database("local a = 1");
+        let game = GameRes::new()
+            .build_time(build_time())
+            .filename("123")
+            .game_lua(&database)
+            .strip_debug_info(true);
+
+        let first = game.build().unwrap();
+        let second = game.clone().build().unwrap();
+        assert_eq!(first, second);
+
+        let unstripped = game.strip_debug_info(false).build().unwrap();
+        assert_ne!(first, unstripped);
+        let adaptor = Bundles::unpack(&first).unwrap();
+        let adaptor = adaptor.get("adaptor.lua").unwrap();
+        assert!(adaptor.find("adaptor.lua").is_none());
+    }
+
+    #[test]
+    fn reuse_encoded_libraries() {
+        let database = database("local a = 1");
+        let game = GameRes::new()
+            .build_time(build_time())
+            .filename("123")
+            .game_lua(&database);
+        let encodes = |libraries: &Libraries| {
+            let before = bundle::ENCODES.with(|n| n.get());
+            game.clone()
+                .libraries(libraries)
+                .bundles()
+                .unwrap()
+                .encoded_entries()
+                .unwrap();
+            bundle::ENCODES.with(|n| n.get()) - before
+        };
+
+        // the first game encodes the libraries along with its own entries
+        let libraries = fresh_libraries();
+        let first = encodes(&libraries);
+        assert!(first > 2);
+        // later games only encode their adaptor and database
+        for _ in 0..3 {
+            assert_eq!(encodes(&libraries), 2);
+            assert_eq!(encodes(&libraries.clone()), 2);
+        }
+        // as before libraries were cached
+        for _ in 0..3 {
+            assert_eq!(encodes(&fresh_libraries()), first);
+        }
+    }
+
+    #[test]
+    #[ignore = "measurement, run with --release --ignored --nocapture"]
+    fn measure_cached_libraries() {
+        let database = database("local a = 1");
+        let game = GameRes::new()
+            .build_time(build_time())
+            .filename("123")
+            .game_lua(&database);
+
+        // libraries encoded on every build, as before they were cached
+        let fresh: Vec<_> = (0..10).map(|_| fresh_libraries()).collect();
+        let uncached = std::time::Instant::now();
+        for libraries in &fresh {
+            game.clone().libraries(libraries).build().unwrap();
+        }
+        let uncached = uncached.elapsed() / 10;
+
+        let libraries = fresh_libraries();
+        game.clone().libraries(&libraries).build().unwrap();
+        let cached = std::time::Instant::now();
+        for _ in 0..10 {
+            game.clone().libraries(&libraries).build().unwrap();
+        }
+        let cached = cached.elapsed() / 10;
+
+        println!("build with uncached libraries: {:?}", uncached);
+        println!("build with cached libraries: {:?}", cached);
+        assert!(cached < uncached);
+    }
+
+    /// Libraries nothing else has encoded yet
+    fn fresh_libraries() -> Libraries {
+        let mut libraries = Libraries::builtin();
+        libraries.set_crypto(crypto::CryptoProfile::dream_maker());
+        libraries
+    }
+
+    /// Unpack by running the loader with a hooked `__U_Lib`
+    fn run_loader(chunk: &[u8]) -> IndexMap<String, Vec<u8>> {
+        let mut chunk = chunk.to_owned();
         crypto::decrypt_res(&mut chunk);
 
         let mut entries = IndexMap::new();
@@ -149,5 +1030,7 @@ mod tests {
             lua.load(&chunk).exec()
         })
         .unwrap();
+
+        entries
     }
 }
@@ -0,0 +1,157 @@
+use include_dir::{include_dir, Dir};
+use indexmap::IndexMap;
+use once_cell::sync::Lazy;
+use std::{borrow::Cow, fmt, fs, io, path::Path, sync::Arc};
+
+use crate::{bundle::Entry, crypto::CryptoProfile};
+
+const BUILDIN_BUNDLED_LIBRARIES_DESC: &[&str] = include!("../static/bundle.txt");
+const BUILDIN_BUNDLED_LIBRARIES: Dir = include_dir!("$CARGO_MANIFEST_DIR/static/bundle");
+
+/// Encoded once for the whole process
+static BUILTIN: Lazy<Libraries> = Lazy::new(|| {
+    let mut entries = IndexMap::with_capacity(BUILDIN_BUNDLED_LIBRARIES_DESC.len());
+    for filename in BUILDIN_BUNDLED_LIBRARIES_DESC {
+        let content = BUILDIN_BUNDLED_LIBRARIES
+            .get_file(filename)
+            .unwrap()
+            .contents();
+        let entry = Entry::new(content, None, CryptoProfile::dream_maker());
+        entries.insert(Cow::Borrowed(*filename), Arc::new(entry));
+    }
+    Libraries {
+        entries,
+        cache: None,
+        crypto: CryptoProfile::dream_maker(),
+    }
+});
+
+/// Libraries bundled into every game, in the order they are packed
+///
+/// Clones share encoded forms of the libraries, so they are only compressed and encrypted once.
+#[derive(Clone)]
+pub struct Libraries {
+    entries: IndexMap<Cow<'static, str>, Arc<Entry>>,
+    cache: Option<Arc<Path>>,
+    crypto: CryptoProfile,
+}
+
+impl Libraries {
+    /// Libraries shipped with the server
+    pub fn builtin() -> Self {
+        BUILTIN.clone()
+    }
+
+    /// Also keep encoded forms in `dir`, so they survive restarts
+    pub fn cache_dir(&mut self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        self.cache = Some(dir.into());
+        self.reset();
+        Ok(())
+    }
+
+    /// Encrypt with `crypto` instead of the keys of DreamMaker, for runtimes built with others
+    pub fn set_crypto(&mut self, crypto: CryptoProfile) {
+        self.crypto = crypto;
+        self.reset();
+    }
+
+    pub fn crypto(&self) -> &CryptoProfile {
+        &self.crypto
+    }
+
+    /// Replace libraries by files of the same name in `dir`, the others are added at the end
+    pub fn overlay(&mut self, dir: &Path) -> io::Result<()> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+
+            let name = entry.file_name().into_string().map_err(|name| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("library name {:?} is not valid unicode", name),
+                )
+            })?;
+            if name.starts_with('.') {
+                continue;
+            }
+            files.push((name, entry.path()));
+        }
+        // keep added libraries in a stable order
+        files.sort();
+
+        for (name, path) in files {
+            let entry = Entry::new(fs::read(path)?, self.cache.clone(), self.crypto.clone());
+            self.entries.insert(Cow::Owned(name), Arc::new(entry));
+        }
+        Ok(())
+    }
+
+    /// Drop a library, return whether it was bundled
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.entries.shift_remove(name).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.entries
+            .iter()
+            .map(|(name, entry)| (name.as_ref(), entry.lua()))
+    }
+
+    pub(crate) fn entries(&self) -> impl Iterator<Item = (&Cow<'static, str>, &Arc<Entry>)> {
+        self.entries.iter()
+    }
+
+    /// Drop encoded forms made with former settings
+    fn reset(&mut self) {
+        for entry in self.entries.values_mut() {
+            let lua = entry.lua().to_owned();
+            *entry = Arc::new(Entry::new(lua, self.cache.clone(), self.crypto.clone()));
+        }
+    }
+}
+
+// contents are too large to be logged
+impl fmt::Debug for Libraries {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.entries.keys()).finish()
+    }
+}
+
+impl Default for Libraries {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlay_libraries() {
+        let dir = std::env::temp_dir().join(format!("dream-tutor-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("Sys.lua"), "-- patched").unwrap();
+        fs::write(dir.join("扩展_新控件.lua"), "-- added").unwrap();
+
+        let mut libraries = Libraries::builtin();
+        let builtin: Vec<_> = libraries.iter().map(|(name, _)| name.to_owned()).collect();
+        libraries.overlay(&dir).unwrap();
+        assert!(libraries.remove("类_鼠标.lua"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        let names: Vec<_> = libraries.iter().map(|(name, _)| name).collect();
+        let sys = builtin.iter().position(|name| name == "Sys.lua").unwrap();
+        assert_eq!(names[sys], "Sys.lua");
+        assert_eq!(names.last(), Some(&"扩展_新控件.lua"));
+        assert!(!names.contains(&"类_鼠标.lua"));
+        assert_eq!(names.len(), builtin.len());
+
+        let (_, sys) = libraries.iter().nth(sys).unwrap();
+        assert_eq!(sys, b"-- patched");
+    }
+}
@@ -0,0 +1,338 @@
+//! Checks of uploaded databases for mistakes the runtime would only hit while playing
+
+use encoding_rs::GBK;
+use std::{collections::HashSet, fmt};
+
+use crate::{
+    bytecode::{self, op, Proto},
+    compile_database,
+    header::HEADER_LEN,
+    header_lines, Error, Libraries, PluginHeader,
+};
+
+/// Table the engine exposes its functions through, `核心` in GBK
+const CORE: &[u8] = b"\xba\xcb\xd0\xc4";
+
+/// Globals of LuaJIT itself
+const STANDARD_GLOBALS: &[&str] = &[
+    "_G",
+    "_VERSION",
+    "assert",
+    "collectgarbage",
+    "dofile",
+    "error",
+    "gcinfo",
+    "getfenv",
+    "getmetatable",
+    "ipairs",
+    "load",
+    "loadfile",
+    "loadstring",
+    "module",
+    "newproxy",
+    "next",
+    "pairs",
+    "pcall",
+    "print",
+    "rawequal",
+    "rawget",
+    "rawset",
+    "require",
+    "select",
+    "setfenv",
+    "setmetatable",
+    "tonumber",
+    "tostring",
+    "type",
+    "unpack",
+    "xpcall",
+    "bit",
+    "coroutine",
+    "debug",
+    "ffi",
+    "io",
+    "jit",
+    "math",
+    "os",
+    "package",
+    "string",
+    "table",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WarningKind {
+    /// Global neither defined by the database nor by any bundled library
+    UndefinedGlobal(String),
+    /// Field of `核心` no bundled library defines
+    UndefinedCoreField(String),
+    /// Illegal keyword appearing in a string constant
+    IllegalKeyword(String),
+    /// Field of the plugin header not padded as DreamMaker pads it
+    IrregularHeader(String),
+    /// Compiled database could not be read, so nothing else is checked
+    Unchecked(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    /// Line in the uploaded file, including the plugin header
+    pub line: Option<usize>,
+    pub kind: WarningKind,
+}
+
+/// Names the bundled libraries provide, collected once and used to check every database
+#[derive(Clone)]
+pub struct Linter {
+    globals: HashSet<Vec<u8>>,
+    core_fields: HashSet<Vec<u8>>,
+}
+
+impl Linter {
+    pub fn new(libraries: &Libraries) -> Self {
+        let mut linter = Self {
+            globals: STANDARD_GLOBALS
+                .iter()
+                .map(|name| name.as_bytes().to_owned())
+                .chain([CORE.to_owned()])
+                .collect(),
+            core_fields: HashSet::new(),
+        };
+
+        // native libraries are not LuaJIT bytecode, and are simply skipped
+        for (name, lua) in libraries
+            .iter()
+            .filter(|(_, lua)| lua.starts_with(b"\x1bLJ"))
+        {
+            match bytecode::parse(lua) {
+                Ok(dump) => {
+                    for proto in &dump.protos {
+                        linter.define(proto);
+                    }
+                }
+                // names it defines are then reported as undefined
+                Err(err) => tracing::warn!("library {} is left out of lint: {}", name, err),
+            }
+        }
+        linter
+    }
+
+    /// Treat `names` as defined, for globals the engine provides natively
+    pub fn known_globals<'a>(mut self, names: impl IntoIterator<Item = &'a str>) -> Self {
+        for name in names {
+            let (name, _, _) = GBK.encode(name);
+            self.globals.insert(name.into_owned());
+        }
+        self
+    }
+
+    /// Check syntax of `database` and report suspicious uses of names and keywords
+    pub fn lint(&self, database: &[u8], keywords: &str) -> Result<Vec<Warning>, Error> {
+        let chunk = compile_database(database)?;
+        Ok(self.lint_compiled(database, &chunk, keywords))
+    }
+
+    /// Report suspicious uses of names and keywords in `chunk`, which `compile_database` made
+    /// of `database`
+    pub fn lint_compiled(&self, database: &[u8], chunk: &[u8], keywords: &str) -> Vec<Warning> {
+        let header = &database[..database.len().min(HEADER_LEN)];
+        let offset = header_lines(header);
+
+        let mut warnings: Vec<_> = PluginHeader::irregularities(database)
+            .into_iter()
+            .map(|reason| Warning {
+                line: None,
+                kind: WarningKind::IrregularHeader(reason.to_owned()),
+            })
+            .collect();
+
+        // lint is advisory, games it fails to read are built all the same
+        let dump = match bytecode::parse(chunk) {
+            Ok(dump) => dump,
+            Err(err) => {
+                warnings.push(Warning {
+                    line: None,
+                    kind: WarningKind::Unchecked(err.to_string()),
+                });
+                return warnings;
+            }
+        };
+
+        // names the database defines itself are fine to use anywhere in it
+        let mut defined = self.clone();
+        for proto in &dump.protos {
+            defined.define(proto);
+        }
+
+        let keywords: Vec<_> = keywords
+            .split(|c: char| c == '|' || c == ',' || c == '，' || c.is_whitespace())
+            .filter(|keyword| !keyword.is_empty())
+            .map(|keyword| (keyword, GBK.encode(keyword).0))
+            .collect();
+
+        for proto in &dump.protos {
+            let line = |pc: usize| proto.line(pc).map(|line| line + offset);
+
+            for (pc, ins) in proto.bc.iter().enumerate() {
+                match ins.op() {
+                    op::GGET => {
+                        let name = proto.kstr(ins.d()).unwrap_or_default();
+                        if !defined.globals.contains(name) {
+                            warnings.push(Warning {
+                                line: line(pc),
+                                kind: WarningKind::UndefinedGlobal(decode(name)),
+                            });
+                        }
+                    }
+                    op::KSTR => {
+                        let s = proto.kstr(ins.d()).unwrap_or_default();
+                        for (keyword, encoded) in &keywords {
+                            if contains(s, encoded) {
+                                warnings.push(Warning {
+                                    line: line(pc),
+                                    kind: WarningKind::IllegalKeyword((*keyword).to_owned()),
+                                });
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            for (pc, field) in core_fields(proto, op::TGETS) {
+                if !defined.core_fields.contains(field) {
+                    warnings.push(Warning {
+                        line: line(pc),
+                        kind: WarningKind::UndefinedCoreField(decode(field)),
+                    });
+                }
+            }
+        }
+
+        warnings
+    }
+
+    fn define(&mut self, proto: &Proto) {
+        for ins in &proto.bc {
+            if ins.op() == op::GSET {
+                if let Some(name) = proto.kstr(ins.d()) {
+                    self.globals.insert(name.to_owned());
+                }
+            }
+        }
+        for (_, field) in core_fields(proto, op::TSETS) {
+            self.core_fields.insert(field.to_owned());
+        }
+    }
+}
+
+impl fmt::Debug for Linter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Linter")
+            .field("globals", &self.globals.len())
+            .field("core_fields", &self.core_fields.len())
+            .finish()
+    }
+}
+
+/// Fields of `核心` accessed by `opcode`, which is either `TGETS` or `TSETS`
+fn core_fields(proto: &Proto, opcode: u8) -> Vec<(usize, &[u8])> {
+    // registers known to hold `核心`, good enough for `核心.f(...)` and `function 核心.f()`
+    let mut core = [false; 256];
+    let mut fields = Vec::new();
+
+    for (pc, ins) in proto.bc.iter().enumerate() {
+        let a = usize::from(ins.a());
+        match ins.op() {
+            op::GGET => core[a] = proto.kstr(ins.d()) == Some(CORE),
+            op::MOV => core[a] = core[usize::from(ins.d() as u8)],
+            code if code == opcode && core[usize::from(ins.b())] => {
+                if let Some(field) = proto.kstr(u16::from(ins.c())) {
+                    fields.push((pc, field));
+                }
+                if opcode == op::TGETS {
+                    core[a] = false;
+                }
+            }
+            // these only read their operand A
+            0..=op::ISF | op::USETV..=op::USETP | op::GSET | op::TSETV..=op::TSETM => {}
+            _ => core[a] = false,
+        }
+    }
+    fields
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+fn decode(name: &[u8]) -> String {
+    GBK.decode_without_bom_handling(name).0.into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lint_database() {
+        if !crate::luajit_2_0() {
+            return;
+        }
+        let source = "local a = 核心.不存在的函数()\n未定义的函数()\nfunction 自定义() end\n自定义('外挂')\n";
+        let mut database = vec![0; HEADER_LEN];
+        database.extend_from_slice(&GBK.encode(source).0);
+
+        let linter = Linter::new(&Libraries::builtin());
+        let warnings = linter.lint(&database, "外挂|加速").unwrap();
+        assert!(warnings.contains(&Warning {
+            line: Some(1),
+            kind: WarningKind::UndefinedCoreField("不存在的函数".to_owned()),
+        }));
+        assert!(warnings.contains(&Warning {
+            line: Some(2),
+            kind: WarningKind::UndefinedGlobal("未定义的函数".to_owned()),
+        }));
+        assert!(warnings.contains(&Warning {
+            line: Some(4),
+            kind: WarningKind::IllegalKeyword("外挂".to_owned()),
+        }));
+        assert_eq!(warnings.len(), 3);
+
+        let linter = linter.known_globals(["未定义的函数"]);
+        assert_eq!(linter.lint(&database, "").unwrap().len(), 1);
+
+        // headers DreamMaker would not write are still linted
+        database[0x30] = b'x';
+        let warnings = linter.lint(&database, "").unwrap();
+        assert!(warnings.contains(&Warning {
+            line: None,
+            kind: WarningKind::IrregularHeader("game name is not padded".to_owned()),
+        }));
+        assert_eq!(warnings.len(), 2);
+    }
+
+    #[test]
+    fn lint_unreadable_chunks() {
+        let mut database = vec![0; HEADER_LEN];
+        database.extend_from_slice(b"local a = 1");
+        let linter = Linter::new(&Libraries::builtin());
+
+        // only failing to compile fails lint
+        let warnings = linter.lint_compiled(&database, b"\x1bLJ\x09", "");
+        assert!(matches!(
+            &warnings[..],
+            [Warning {
+                line: None,
+                kind: WarningKind::Unchecked(_),
+            }]
+        ));
+        assert!(linter.lint(&database, "").is_ok());
+        database.extend_from_slice(b" = 2");
+        assert!(matches!(
+            linter.lint(&database, ""),
+            Err(Error::Syntax { line: Some(1), .. })
+        ));
+    }
+}
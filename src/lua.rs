@@ -1,19 +1,75 @@
 use bstr::BString;
 use mlua::Lua;
+use once_cell::sync::Lazy;
 
-pub fn compile(name: impl AsRef<str>, chunk: impl AsRef<[u8]>) -> Result<Vec<u8>, mlua::Error> {
+use crate::Error;
+
+/// `jit.version` of the linked LuaJIT, and the version of bytecode it dumps
+static RUNTIME: Lazy<(String, u8)> = Lazy::new(|| {
+    let lua = unsafe { Lua::unsafe_new() };
+    let (name, dump): (String, BString) = lua
+        .load("return jit.version, string.dump(function() end)")
+        .eval()
+        .expect("failed to dump an empty function");
+    (name, dump[3])
+});
+
+/// Fail unless the linked LuaJIT dumps bytecode of `version`
+pub fn check_dump_version(version: u8) -> Result<(), Error> {
+    let (name, dumped) = &*RUNTIME;
+    if *dumped == version {
+        Ok(())
+    } else {
+        Err(Error::UnsupportedLuaJit(name.clone()))
+    }
+}
+
+/// Compile `chunk` to bytecode, without line numbers and names of variables if `strip`
+pub fn compile(
+    name: impl AsRef<str>,
+    chunk: impl AsRef<[u8]>,
+    strip: bool,
+) -> Result<Vec<u8>, Error> {
+    let name = name.as_ref();
     let lua = unsafe { Lua::unsafe_new() };
 
     let f = lua
         .load(chunk.as_ref())
         .set_name(name)?
         .into_function()
-        .unwrap();
+        .map_err(|err| match err {
+            mlua::Error::SyntaxError { message, .. } => syntax_error(name, message),
+            err => Error::Lua(err),
+        })?;
 
     let data: BString = lua
         .load("return string.dump")
         .eval::<mlua::Function>()
         .unwrap()
-        .call(f)?;
+        .call((f, strip))?;
     Ok(data.into())
 }
+
+/// Split `[string "name"]:line: message` reported by LuaJIT
+fn syntax_error(chunk: &str, message: String) -> Error {
+    let prefix = format!("[string \"{}\"]:", chunk);
+    let located = message
+        .strip_prefix(&prefix)
+        .and_then(|rest| rest.split_once(':'))
+        .and_then(|(line, rest)| Some((line.parse().ok()?, rest.trim_start())));
+
+    match located {
+        Some((line, rest)) => Error::Syntax {
+            chunk: chunk.to_owned(),
+            line: Some(line),
+            message: rest.to_owned(),
+            excerpt: Vec::new(),
+        },
+        None => Error::Syntax {
+            chunk: chunk.to_owned(),
+            line: None,
+            message,
+            excerpt: Vec::new(),
+        },
+    }
+}
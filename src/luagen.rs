@@ -0,0 +1,119 @@
+//! Generation of Lua source from values which are not trusted to be valid Lua
+//!
+//! Values only get into templates as [`Expr`], which are made by the functions of this module.
+
+use encoding_rs::GBK;
+use std::fmt::{self, Write};
+
+use crate::Error;
+
+/// Lua expression safe to be spliced into source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expr(String);
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// String literal of `value`, with everything but printable ASCII escaped
+///
+/// The literal is plain ASCII, so it survives any encoding the source is saved in, even GBK
+/// where the second byte of a character may be a backslash.
+pub fn string(value: &[u8]) -> Expr {
+    let mut s = String::with_capacity(value.len() + 2);
+    s.push('"');
+    for &b in value {
+        match b {
+            b'"' | b'\\' => {
+                s.push('\\');
+                s.push(b as char);
+            }
+            b' '..=b'~' => s.push(b as char),
+            // three digits, so a digit after it is not taken as part of the escape
+            _ => write!(s, "\\{:03}", b).unwrap(),
+        }
+    }
+    s.push('"');
+    Expr(s)
+}
+
+/// String literal of `value` encoded in GBK, as the runtime expects text
+pub fn gbk_string(value: &str, what: &str) -> Result<Expr, Error> {
+    let (encoded, _, had_errors) = GBK.encode(value);
+    if had_errors {
+        return Err(Error::Encoding(what.to_owned()));
+    }
+    Ok(string(&encoded))
+}
+
+pub fn integer(value: impl Into<i64>) -> Expr {
+    Expr(value.into().to_string())
+}
+
+pub fn boolean(value: bool) -> Expr {
+    Expr(value.to_string())
+}
+
+pub fn nil() -> Expr {
+    Expr("nil".to_owned())
+}
+
+/// Lua source with `{{name}}` placeholders
+#[derive(Debug, Clone, Copy)]
+pub struct Template(&'static str);
+
+impl Template {
+    pub const fn new(source: &'static str) -> Self {
+        Self(source)
+    }
+
+    /// Replace every placeholder by the value of the same name
+    ///
+    /// Templates are part of the server, so a placeholder without a value is a bug and panics.
+    pub fn render(&self, values: &[(&str, &Expr)]) -> String {
+        let mut out = String::with_capacity(self.0.len());
+        let mut rest = self.0;
+        while let Some(start) = rest.find("{{") {
+            out.push_str(&rest[..start]);
+            let end = rest[start..]
+                .find("}}")
+                .unwrap_or_else(|| panic!("unclosed placeholder in template"));
+            let name = rest[start + 2..start + end].trim();
+            let (_, value) = values
+                .iter()
+                .find(|(n, _)| *n == name)
+                .unwrap_or_else(|| panic!("no value of placeholder {}", name));
+            out.push_str(&value.0);
+            rest = &rest[start + end + 2..];
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mlua::Lua;
+
+    use super::*;
+
+    #[test]
+    fn escape_string_literals() {
+        let values: &[&[u8]] = &[
+            b"",
+            b"\"]] os.exit(1) --[[",
+            b"\\\r\n\0\x7f\xff1",
+            // second byte of `\x95\x5c` is a backslash
+            b"\x95\x5c\"",
+        ];
+        let lua = Lua::new();
+        for &value in values {
+            let source = Template::new("return {{ value }}").render(&[("value", &string(value))]);
+            assert!(source.is_ascii());
+            let s: mlua::String = lua.load(&source).eval().unwrap();
+            assert_eq!(s.as_bytes(), value);
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use std::{borrow::Cow, collections::HashMap, fmt::Debug, sync::Arc, time::Duration};
+use std::{borrow::Cow, fmt::Debug, io, path::PathBuf, sync::Arc, time::Duration};
 
 use async_session::{MemoryStore, Session, SessionStore};
 use async_trait::async_trait;
@@ -11,27 +11,151 @@ use axum::{
     BoxError, Extension, Form, Router,
 };
 use axum_extra::extract::CookieJar;
-use dream_tutor::{crypto, GameRes};
+use clap::{Parser, Subcommand};
+use command::{BuildArgs, UnpackArgs};
+use config::{Config, LogFormat, StorageBackend};
+use dream_tutor::{
+    crypto, CheatResponse, Error as BuildError, GameRes, Libraries, Linter, PluginHeader, Warning,
+    WarningKind, PROTECT_FUNCTION,
+};
 use encoding_rs::GBK;
-use hyper::{HeaderMap, StatusCode};
+use hyper::{header, HeaderMap, StatusCode};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use serde::{Deserialize, Serialize};
-use tokio::sync::RwLock;
+use storage::{CompileResult, DiskStorage, MemoryStorage, Storage, UpdateFiles};
 use tower::ServiceBuilder;
-use tower_http::trace::TraceLayer;
+use tower_http::{limit::RequestBodyLimitLayer, trace::TraceLayer};
+use worker::{CompileQueue, Job, QueueSettings};
 
-use tracing::metadata::LevelFilter;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer};
+use users::{UserCommand, UserRegistry};
+
+mod config;
+
+mod storage;
+
+mod update;
+
+mod worker;
+
+mod users;
+
+mod command;
+
+mod upload;
+
+mod download;
+
+mod statistics;
+
+mod tasks;
+
+#[derive(Debug, Parser)]
+#[clap(version, about)]
+struct Cli {
+    /// TOML config file, `dream-tutor.toml` is read if exists
+    #[clap(short, long, env = "DREAM_TUTOR_CONFIG")]
+    config: Option<PathBuf>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Start the build server, the default
+    Serve,
+    /// Manage accounts allowed to login
+    #[clap(subcommand)]
+    User(UserCommand),
+    /// Compile a game database into a packed game without the server
+    Build(BuildArgs),
+    /// Extract entries of a packed game for inspection
+    Unpack(UnpackArgs),
+}
 
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer().with_filter(LevelFilter::TRACE))
-        .init();
+    let cli = Cli::parse();
+
+    let config = Config::load(cli.config.as_deref()).unwrap_or_else(|err| {
+        eprintln!("error: {}", err);
+        std::process::exit(1);
+    });
+
+    // builds work without the storage of the server
+    let open_users = || async {
+        UserRegistry::open(config.users_path())
+            .await
+            .expect("failed to open user registry")
+    };
+
+    let command = cli.command.unwrap_or(Command::Serve);
+    // other versions link as well, and only fail once games are built
+    if !matches!(command, Command::User(_)) {
+        if let Err(err) = dream_tutor::check_luajit() {
+            eprintln!("error: {}", err);
+            std::process::exit(1);
+        }
+    }
+
+    match command {
+        Command::Serve => {
+            let users = open_users().await;
+            serve(config, users).await
+        }
+        Command::User(command) => {
+            if let Err(err) = command.run(&open_users().await).await {
+                eprintln!("error: {}", err);
+                std::process::exit(1);
+            }
+        }
+        Command::Build(args) => {
+            if let Err(err) = args.run(config.build) {
+                eprintln!("error: {}", err);
+                std::process::exit(1);
+            }
+        }
+        Command::Unpack(args) => {
+            if let Err(err) = args.run(config.build.bundled.crypto()) {
+                eprintln!("error: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+async fn serve(config: Config, users: UserRegistry) {
+    let fmt = tracing_subscriber::fmt::layer();
+    match config.log.format {
+        LogFormat::Text => tracing_subscriber::registry()
+            .with(fmt.with_filter(config.log.level))
+            .init(),
+        LogFormat::Json => tracing_subscriber::registry()
+            .with(fmt.json().with_filter(config.log.level))
+            .init(),
+    }
+
+    if users.is_empty().await {
+        tracing::warn!("no user is able to login, add one by `dream-tutor user add`");
+    }
+
+    let storage: Arc<dyn Storage> = match config.storage.backend {
+        StorageBackend::Memory => Arc::new(MemoryStorage::default()),
+        StorageBackend::Disk => Arc::new(
+            DiskStorage::open(&config.storage.path)
+                .await
+                .expect("failed to open storage"),
+        ),
+    };
+
+    let state = SharedState::new(storage, users, config.build, &config.queue);
 
     let dev_routes = Router::new()
         .route("/index.php", post(dev_index).get(dev_index))
-        .route("/api/upload.php", post(upload));
+        .route(
+            "/api/upload.php",
+            post(upload).layer(RequestBodyLimitLayer::new(config.server.max_upload)),
+        );
 
     let bbs_routes = Router::new().route("/uc_server/avatar.php", get(avatar));
 
@@ -39,19 +163,23 @@ async fn main() {
         .route("/filelist/lock_filelist1.txt", get(filelist))
         .nest("/dmdev", dev_routes)
         .nest("/dmbbs", bbs_routes)
+        .nest("/dmupdate", update::routes())
+        .nest("/dmstat", statistics::routes())
+        .nest("/dmtask", tasks::routes())
         .layer(
             ServiceBuilder::new()
                 // Handle errors from middleware
                 .layer(HandleErrorLayer::new(handle_error))
                 .load_shed()
-                .concurrency_limit(1024)
-                .timeout(Duration::from_secs(10))
+                .concurrency_limit(config.server.concurrency)
+                .timeout(Duration::from_secs(config.server.timeout))
                 .layer(TraceLayer::new_for_http())
-                .layer(Extension(Arc::new(SharedState::default())))
+                .layer(Extension(Arc::new(state)))
                 .into_inner(),
         );
 
-    axum::Server::bind(&"0.0.0.0:3000".parse().unwrap())
+    tracing::info!("listening on {}", config.server.bind);
+    axum::Server::bind(&config.server.bind)
         .serve(app.into_make_service())
         .await
         .unwrap();
@@ -60,20 +188,126 @@ async fn main() {
 #[derive(Debug)]
 struct SharedState {
     store: MemoryStore,
-    files: RwLock<HashMap<String, Box<[u8]>>>,
-    results: RwLock<Vec<Result<Box<[u8]>, String>>>,
+    storage: Arc<dyn Storage>,
+    users: UserRegistry,
+    build: Arc<BuildSettings>,
+    queue: CompileQueue,
 }
 
-impl Default for SharedState {
-    fn default() -> Self {
+impl SharedState {
+    /// Should be called inside a tokio runtime, which compile workers are spawned on
+    fn new(
+        storage: Arc<dyn Storage>,
+        users: UserRegistry,
+        build: BuildSettings,
+        queue: &QueueSettings,
+    ) -> Self {
+        let build = Arc::new(build);
+        let queue = CompileQueue::start(queue, storage.clone(), build.clone());
         Self {
             store: MemoryStore::new(),
-            files: Default::default(),
-            results: Default::default(),
+            storage,
+            users,
+            build,
+            queue,
+        }
+    }
+}
+
+/// Whether tests building games are able to run, as only LuaJIT 2.0 builds them
+#[cfg(test)]
+fn luajit_2_0() -> bool {
+    let checked = dream_tutor::check_luajit();
+    if let Err(err) = &checked {
+        eprintln!("skipped: {}", err);
+    }
+    checked.is_ok()
+}
+
+#[cfg(test)]
+impl SharedState {
+    /// State of a server keeping everything in memory, no one is able to login
+    async fn in_memory() -> Arc<Self> {
+        let users = std::env::temp_dir().join(format!("dream-tutor-{}.json", uuid::Uuid::new_v4()));
+        let users = UserRegistry::open(users).await.unwrap();
+        Arc::new(Self::new(
+            Arc::new(MemoryStorage::default()),
+            users,
+            BuildSettings::default(),
+            &QueueSettings::default(),
+        ))
+    }
+}
+
+/// Server side settings applied to every build
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct BuildSettings {
+    /// `host` and `port` of the server which login games connect to
+    #[serde(deserialize_with = "config::login_server")]
+    login_server: Option<(String, u16)>,
+    /// Url of this server reachable from games, auto update games fetch resources from it
+    public_url: String,
+    /// Engine version of the bundled runtime, databases of other versions are rejected
+    engine_version: Option<u32>,
+    libraries: config::LibrarySettings,
+    /// Ciphers the bundled runtime decrypts games with
+    crypto: config::CryptoSettings,
+    /// Libraries loaded as `libraries` describes
+    #[serde(skip)]
+    bundled: Libraries,
+    /// Globals the engine provides natively, not to be warned about
+    known_globals: Vec<String>,
+    /// Checker of databases against `bundled`
+    #[serde(skip)]
+    linter: Linter,
+    /// Build byte-identical games from identical inputs, stamped with `source_date_epoch`
+    reproducible: bool,
+    /// Build time of reproducible builds in seconds since UNIX epoch, as `SOURCE_DATE_EPOCH`
+    source_date_epoch: i64,
+    /// Leave line numbers and names of variables out of games
+    strip: bool,
+    /// Encrypt string constants of databases and rename their locals
+    obfuscate: bool,
+    /// Largest database accepted after decompression, in bytes
+    max_database_size: u64,
+    /// What games do once they detect a cheat
+    cheat_response: CheatResponse,
+}
+
+impl Default for BuildSettings {
+    fn default() -> Self {
+        Self {
+            login_server: None,
+            public_url: "http://127.0.0.1:3000".to_owned(),
+            engine_version: None,
+            libraries: Default::default(),
+            crypto: Default::default(),
+            bundled: Libraries::builtin(),
+            known_globals: Vec::new(),
+            linter: Linter::new(&Libraries::builtin()),
+            reproducible: false,
+            source_date_epoch: 0,
+            strip: false,
+            obfuscate: false,
+            max_database_size: crypto::DEFAULT_LIMIT,
+            cheat_response: CheatResponse::default(),
         }
     }
 }
 
+impl BuildSettings {
+    /// Time stamped into a game submitted at `submitted`
+    fn build_time(&self, submitted: time::PrimitiveDateTime) -> time::PrimitiveDateTime {
+        if !self.reproducible {
+            return submitted;
+        }
+        let fixed = time::OffsetDateTime::from_unix_timestamp(self.source_date_epoch)
+            .unwrap_or(time::OffsetDateTime::UNIX_EPOCH);
+        time::PrimitiveDateTime::new(fixed.date(), fixed.time())
+    }
+}
+
 mod num_bool {
     use serde::{
         de::{Error, Unexpected},
@@ -102,7 +336,7 @@ mod num_bool {
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Deserialize)]
 struct User {
     c: String,
     a: String,
@@ -110,6 +344,17 @@ struct User {
     password: String,
 }
 
+// keep password out of logs
+impl Debug for User {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("User")
+            .field("c", &self.c)
+            .field("a", &self.a)
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, TryFromPrimitive, IntoPrimitive)]
 #[repr(u32)]
 #[serde(try_from = "u32", into = "u32")]
@@ -119,7 +364,9 @@ enum CompileStatus {
     Done = 2,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, TryFromPrimitive, IntoPrimitive)]
+#[derive(
+    Debug, Clone, Serialize, Deserialize, TryFromPrimitive, IntoPrimitive, clap::ValueEnum,
+)]
 #[repr(u32)]
 #[serde(try_from = "u32", into = "u32")]
 enum GameType {
@@ -130,7 +377,7 @@ enum GameType {
 
 time::serde::format_description!(no_sub_second, PrimitiveDateTime, "[year]-[month]-[day] [hour]:[minute]:[second]");
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct CompileTask {
     id: u32,
     filename: String,
@@ -141,6 +388,15 @@ struct CompileTask {
     #[serde(with = "num_bool")]
     op_qudong: bool,
     ver: u32,
+    /// Plugin info of the submitted database, if it could be parsed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    header: Option<PluginHeader>,
+    /// Problems found in the database which did not stop the build
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<String>,
+    /// Secret the built games report launches with, so that no one else counts for them
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    report_token: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -150,7 +406,7 @@ struct CompileOption {
     filename: String,
     #[serde(with = "num_bool")]
     op_safedata: bool,
-    /// Unknow, so just ignored
+    /// Remove the DreamMaker banner, which games cannot do yet, so accepted either way and ignored
     #[serde(with = "num_bool")]
     #[allow(unused)]
     op_delad: bool,
@@ -159,6 +415,7 @@ struct CompileOption {
     #[serde(with = "num_bool")]
     op_jiasu: bool,
     op_keywords: String,
+    /// Build for runtimes with their driver enabled
     #[serde(with = "num_bool")]
     op_qudong: bool,
     op_login: GameType,
@@ -239,6 +496,7 @@ async fn dev_index(
     Extension(state): Extension<Arc<SharedState>>,
     func: IndexAction,
     jar: CookieJar,
+    headers: HeaderMap,
 ) -> Response {
     tracing::trace!("dev_index");
     match func {
@@ -246,7 +504,7 @@ async fn dev_index(
         IndexAction::Submit(opt) => submit_compile(state, opt, jar).await.into_response(),
         IndexAction::GetList => get_compile_list(state, jar).await.into_response(),
         IndexAction::GetReason(id) => get_fail_reason(state, jar, id).await.into_response(),
-        IndexAction::Download(id) => download(state, jar, id).await.into_response(),
+        IndexAction::Download(id) => download(state, jar, id, &headers).await.into_response(),
     }
 }
 
@@ -254,18 +512,23 @@ async fn dev_index(
 async fn dev_login(
     state: Arc<SharedState>,
     user: User,
-) -> Result<(HeaderMap, &'static str), (StatusCode, &'static str)> {
+) -> Result<(HeaderMap, String), (StatusCode, &'static str)> {
     // assertion: client should only request login as a member
     if user.a != "new_sw_login" || user.c != "member" {
         return Err((StatusCode::INTERNAL_SERVER_ERROR, "assertion failed"));
     }
-    if user.username != "xyxx" || user.password != "xyxx" {
-        return Err((StatusCode::FORBIDDEN, "incorrect username or password"));
-    }
+    let account = state
+        .users
+        .verify(&user.username, &user.password)
+        .await
+        .ok_or((StatusCode::FORBIDDEN, "incorrect username or password"))?;
 
     // create a new session for the login for this time
     let session_id = {
-        let session = Session::new();
+        let mut session = Session::new();
+        session
+            .insert("uid", account.uid)
+            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "failed to store session"))?;
         let session_cookie = state
             .store
             .store_session(session)
@@ -290,9 +553,9 @@ async fn dev_login(
             .unwrap(),
     );
 
-    // 1 for uid, 76 for user group
-    const USER_INFO: &str = "ok|1|2|76";
-    Ok((header, USER_INFO))
+    // uid and user group
+    let user_info = format!("ok|{}|2|{}", account.uid, account.group);
+    Ok((header, user_info))
 }
 
 #[tracing::instrument]
@@ -321,43 +584,243 @@ async fn check_session(store: &MemoryStore, jar: CookieJar) -> Result<Session, S
         .ok_or(StatusCode::UNAUTHORIZED)
 }
 
-fn check_id_in_session(id: u32, session: Session) -> Result<(), StatusCode> {
-    session
-        .get::<HashMap<u32, CompileTask>>("tasks")
-        .and_then(|t| t.contains_key(&id).then_some(()))
+fn session_uid(session: &Session) -> Result<u32, StatusCode> {
+    session.get("uid").ok_or(StatusCode::UNAUTHORIZED)
+}
+
+fn storage_error(err: io::Error) -> StatusCode {
+    match err.kind() {
+        // names storage cannot keep are never found
+        io::ErrorKind::NotFound | io::ErrorKind::InvalidInput => StatusCode::NOT_FOUND,
+        _ => {
+            tracing::error!("storage error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+async fn check_task_owner(storage: &dyn Storage, uid: u32, id: u32) -> Result<(), StatusCode> {
+    storage
+        .load_tasks(uid)
+        .await
+        .map_err(storage_error)?
+        .contains_key(&id)
+        .then_some(())
         .ok_or(StatusCode::FORBIDDEN)
 }
 
-fn compile(
+/// Outcome of a build, as it is stored
+struct Artifact {
+    result: CompileResult,
+    update: Option<UpdateFiles>,
+    warnings: Vec<String>,
+}
+
+/// Build and compress the game into the artifact the client downloads
+///
+/// Games built for `report`, the id of a task of user `uid` and its report token, report launches
+/// to the server, and auto update games fetch the resources published for `uid`.
+fn build_artifact(
     file: &[u8],
     option: &CompileOption,
     build_time: time::PrimitiveDateTime,
-) -> Result<Box<[u8]>, String> {
-    // only offline mode supported for now
-    if !matches!(option.op_login, GameType::Offline) {
-        return Err("unsupported game type".to_owned());
+    uid: Option<u32>,
+    report: Option<(u32, &str)>,
+    settings: &BuildSettings,
+) -> Artifact {
+    match compile(file, option, build_time, uid, report, settings) {
+        Ok(compiled) => {
+            let mut buf = Vec::new();
+            let result = crypto::compress(&compiled.packed, &mut buf)
+                .map(|_| buf.into_boxed_slice())
+                .map_err(|err| fail_reason(&BuildError::Compression(err)));
+            Artifact {
+                result,
+                update: compiled.update,
+                warnings: compiled.warnings.iter().map(warning_text).collect(),
+            }
+        }
+        Err(err) => Artifact {
+            result: Err(fail_reason(&err)),
+            update: None,
+            warnings: Vec::new(),
+        },
     }
+}
 
-    if !option.op_delad {
-        return Err("unknown option delad".to_owned());
+/// Describe a lint warning in Chinese, as `fail_reason`
+fn warning_text(warning: &Warning) -> String {
+    let text = match &warning.kind {
+        WarningKind::UndefinedGlobal(name) => format!("使用了未定义的全局变量 {}", name),
+        WarningKind::UndefinedCoreField(field) => format!("使用了未定义的 核心.{}", field),
+        WarningKind::IllegalKeyword(keyword) => format!("字符串中含有非法关键字 {}", keyword),
+        WarningKind::IrregularHeader(reason) => format!("游戏数据文件头不规范（{}）", reason),
+        WarningKind::Unchecked(reason) => format!("未能检查游戏脚本（{}）", reason),
+    };
+    match warning.line {
+        Some(line) => format!("第 {} 行：{}", line, text),
+        None => text,
     }
+}
 
-    if !option.op_jiasu && !option.op_qudong && !option.op_safedata {
-        return Err("unsupported option".to_owned());
+/// Explain a failed build to the game author, in Chinese as the client is
+fn fail_reason(err: &BuildError) -> String {
+    match err {
+        BuildError::InvalidHeader(reason) => {
+            format!("游戏数据文件头无效（{}），请重新上传", reason)
+        }
+        BuildError::EngineVersion { expected, found } => format!(
+            "游戏使用的引擎版本 {} 与服务器的引擎版本 {} 不一致，请更新编辑器后重试",
+            found, expected
+        ),
+        BuildError::Syntax {
+            chunk,
+            line,
+            message,
+            excerpt,
+        } => {
+            let chunk = match chunk.as_str() {
+                "database.lua" => "游戏脚本",
+                chunk => chunk,
+            };
+            let mut s = match line {
+                Some(line) => format!("{} 第 {} 行存在语法错误：{}", chunk, line, message),
+                None => format!("{} 存在语法错误：{}", chunk, message),
+            };
+            for (n, text) in excerpt {
+                let mark = if Some(*n) == *line { '>' } else { ' ' };
+                s.push_str(&format!("\n{} {:>5} | {}", mark, n, text));
+            }
+            s
+        }
+        BuildError::Obfuscation { line } => format!(
+            "游戏脚本第 {} 行的字符串无法加密，请检查其中的转义字符，或关闭混淆后重试",
+            line
+        ),
+        BuildError::Lua(err) => format!("编译失败：{}", err),
+        BuildError::UnsupportedLuaJit(name) => {
+            format!("服务器使用的 {} 无法编译游戏，需要 LuaJIT 2.0", name)
+        }
+        BuildError::Compression(err) => format!("压缩游戏数据失败：{}", err),
+        BuildError::Encoding(what) => format!("{}含有无法以 GBK 编码的字符", what),
+        BuildError::MissingField("login_server") => "服务器未配置登录服务器".to_owned(),
+        BuildError::MissingField(field) => format!("缺少编译参数 {}", field),
+    }
+}
+
+// maybe use local time zone in future?
+fn now() -> time::PrimitiveDateTime {
+    let offseted = time::OffsetDateTime::now_utc();
+    time::PrimitiveDateTime::new(offseted.date(), offseted.time())
+}
+
+struct Compiled {
+    packed: Box<[u8]>,
+    /// Resources served to auto update games
+    update: Option<UpdateFiles>,
+    warnings: Vec<Warning>,
+}
+
+fn compile(
+    file: &[u8],
+    option: &CompileOption,
+    build_time: time::PrimitiveDateTime,
+    uid: Option<u32>,
+    report: Option<(u32, &str)>,
+    settings: &BuildSettings,
+) -> Result<Compiled, BuildError> {
+    // syntax errors fail the build, the chunk is then linted and built as it is
+    let chunk = dream_tutor::compile_database(file)?;
+    let mut warnings = settings
+        .linter
+        .lint_compiled(file, &chunk, &option.op_keywords);
+    if option.op_safedata {
+        // defined by the adaptor of games protected from memory cheats
+        warnings.retain(|warning| {
+            !matches!(&warning.kind, WarningKind::UndefinedGlobal(name) if name == PROTECT_FUNCTION)
+        });
     }
 
     // build game resources
-    GameRes::new()
+    let game = GameRes::new()
         .illegal_keywords(&option.op_keywords)
         .anti_memory_cheat(option.op_safedata)
         .anti_speed_hack(option.op_jiasu)
+        .cheat_response(settings.cheat_response)
+        .driver(option.op_qudong)
         .statistics(option.op_statistics)
         .build_time(build_time)
         .filename(&option.filename)
+        .libraries(&settings.bundled)
+        .strip_debug_info(settings.strip || settings.reproducible)
+        .obfuscate(settings.obfuscate)
         .game_lua(file)
-        .build()
-        .map(|v| v.into_boxed_slice())
-        .map_err(|err| err.to_string())
+        .compiled_database(&chunk);
+
+    let game = match settings.engine_version {
+        Some(ver) => game.engine_version(ver),
+        None => game,
+    };
+
+    let report = match (report, uid) {
+        (Some((id, token)), Some(uid)) => {
+            let url = format!(
+                "{}/dmstat/report?gid={}&uid={}&token={}",
+                settings.public_url.trim_end_matches('/'),
+                id,
+                uid,
+                token
+            );
+            Some((url, id, uid))
+        }
+        _ => None,
+    };
+    let game = match &report {
+        Some((url, id, uid)) => game.statistics_report(url, *id, *uid),
+        None => game,
+    };
+
+    // resources are published for the owner, so others cannot replace them by the same name
+    let update_url = uid.map(|uid| update::update_url(&settings.public_url, uid, &option.filename));
+
+    let game = match option.op_login {
+        GameType::Offline => game,
+        GameType::Login => {
+            let (host, port) = settings
+                .login_server
+                .as_ref()
+                .ok_or(BuildError::MissingField("login_server"))?;
+            game.login_server(host, *port)
+        }
+        GameType::AutoUpdate => {
+            let url = update_url
+                .as_ref()
+                .ok_or(BuildError::MissingField("owner"))?;
+            game.auto_update(url, option.ver)
+        }
+    };
+
+    let bundles = game.bundles()?;
+
+    let update = match option.op_login {
+        GameType::AutoUpdate => {
+            let entries = bundles.encoded_entries()?;
+            let files = entries
+                .into_iter()
+                .map(|(name, data)| (name.to_owned(), data.into()))
+                .collect();
+            Some(files)
+        }
+        _ => None,
+    };
+
+    let packed = bundles.pack()?;
+
+    Ok(Compiled {
+        packed: packed.into_boxed_slice(),
+        update,
+        warnings,
+    })
 }
 
 #[tracing::instrument]
@@ -366,61 +829,61 @@ async fn submit_compile(
     option: CompileOption,
     jar: CookieJar,
 ) -> Result<&'static str, StatusCode> {
-    let mut session = check_session(&state.store, jar).await?;
+    let session = check_session(&state.store, jar).await?;
+    let uid = session_uid(&session)?;
 
     // get pre-upload game data file
-    let files = state.files.read().await;
-    let file = files
-        .get(&option.filename)
+    let file = state
+        .storage
+        .load_file(&option.filename)
+        .await
+        .map_err(storage_error)?
         .ok_or(StatusCode::PRECONDITION_REQUIRED)?;
 
-    // maybe use local time zone in future?
-    let build_time = {
-        let offseted = time::OffsetDateTime::now_utc();
-        time::PrimitiveDateTime::new(offseted.date(), offseted.time())
-    };
+    let build_time = now();
 
-    // compile start get compile status
-    let result = compile(file, &option, build_time).and_then(|bytes| {
-        let mut buf = Vec::new();
-        crypto::compress(&bytes, &mut buf)
-            .map(|_| buf.into_boxed_slice())
-            .map_err(|err| err.to_string())
-    });
-    let status = match result {
-        Ok(_) => CompileStatus::Done,
-        Err(_) => CompileStatus::Failed,
+    // record the compilation detial under the user for client querying,
+    // workers update its status once the compilation finished
+    let id = state.storage.next_id().await.map_err(storage_error)?;
+    let task = CompileTask {
+        id,
+        filename: option.filename.clone(),
+        addtime: build_time,
+        status: CompileStatus::Processing,
+        op_login: option.op_login.clone(),
+        op_qudong: option.op_qudong,
+        ver: option.ver,
+        header: PluginHeader::parse(&file).ok(),
+        warnings: Vec::new(),
+        report_token: uuid::Uuid::new_v4().simple().to_string(),
     };
+    state
+        .storage
+        .store_task(uid, task.clone())
+        .await
+        .map_err(storage_error)?;
 
-    // push compilation result into results
-    // get a id for future use
-    let id = {
-        let mut results = state.results.write().await;
-        let id = results.len() as u32;
-        results.push(result);
-        id
+    let job = Job {
+        uid,
+        task,
+        option,
+        file,
     };
-
-    // push the compilation detial into session for client querying
-    let mut tasks: HashMap<u32, CompileTask> = session.get("tasks").unwrap_or_default();
-
-    tasks.insert(
-        id,
-        CompileTask {
-            id,
-            filename: option.filename,
-            addtime: build_time,
-            status,
-            op_login: option.op_login,
-            op_qudong: option.op_qudong,
-            ver: option.ver,
-        },
-    );
-
-    session.insert("tasks", tasks).map_err(|err| {
-        tracing::error!("insert task error: {:?}", err);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    if let Err(job) = state.queue.submit(job) {
+        let Job { mut task, .. } = *job;
+        tracing::warn!("compile queue is full, reject task {}", id);
+        state
+            .storage
+            .store_result(id, Err("编译队列已满，请稍后再试".to_owned()))
+            .await
+            .map_err(storage_error)?;
+        task.status = CompileStatus::Failed;
+        state
+            .storage
+            .store_task(uid, task)
+            .await
+            .map_err(storage_error)?;
+    }
 
     Ok("ok")
 }
@@ -428,13 +891,20 @@ async fn submit_compile(
 #[tracing::instrument]
 async fn get_compile_list(state: Arc<SharedState>, jar: CookieJar) -> Result<String, StatusCode> {
     let session = check_session(&state.store, jar).await?;
+    let uid = session_uid(&session)?;
 
-    // get compilation tasks in this session, empty list by default
-    let tasks = session.get_raw("tasks").unwrap_or_default();
+    // get compilation tasks of this user, empty list by default
+    let tasks = state.storage.load_tasks(uid).await.map_err(storage_error)?;
 
     let mut s = String::new();
     s.push_str("ok");
-    s.push_str(&tasks);
+    if !tasks.is_empty() {
+        let tasks = tasks::list(&tasks).map_err(|err| {
+            tracing::error!("serialize tasks error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        s.push_str(&tasks);
+    }
     Ok(s)
 }
 
@@ -443,20 +913,32 @@ async fn get_fail_reason(
     state: Arc<SharedState>,
     jar: CookieJar,
     id: u32,
-) -> Result<String, StatusCode> {
+) -> Result<impl IntoResponse, StatusCode> {
     tracing::trace!("id = {:?}", id);
 
     let session = check_session(&state.store, jar).await?;
-    check_id_in_session(id, session)?;
+    let uid = session_uid(&session)?;
+    check_task_owner(state.storage.as_ref(), uid, id).await?;
 
-    let results = state.results.read().await;
-    results
-        .get(id as usize)
+    let reason = state
+        .storage
+        .load_result(id)
+        .await
+        .map_err(storage_error)?
         .ok_or(StatusCode::NOT_FOUND)?
-        .as_ref()
         .err()
-        .cloned()
-        .ok_or(StatusCode::PRECONDITION_FAILED)
+        .ok_or(StatusCode::PRECONDITION_FAILED)?;
+
+    Ok(gbk_text(&reason))
+}
+
+/// Text the client shows as is, in GBK
+fn gbk_text(text: &str) -> impl IntoResponse {
+    let (text, _, _) = GBK.encode(text);
+    (
+        [(header::CONTENT_TYPE, "text/plain; charset=gbk")],
+        text.into_owned(),
+    )
 }
 
 #[tracing::instrument]
@@ -464,19 +946,31 @@ async fn download(
     state: Arc<SharedState>,
     jar: CookieJar,
     id: u32,
-) -> Result<Vec<u8>, (StatusCode, &'static str)> {
+    headers: &HeaderMap,
+) -> Result<Response, (StatusCode, &'static str)> {
     let session = check_session(&state.store, jar)
         .await
         .map_err(|code| (code, "invalid session"))?;
-    check_id_in_session(id, session).map_err(|code| (code, "invalid id"))?;
+    let uid = session_uid(&session).map_err(|code| (code, "invalid session"))?;
+    let task = state
+        .storage
+        .load_tasks(uid)
+        .await
+        .map_err(|err| (storage_error(err), "failed to load tasks"))?
+        .remove(&id)
+        .ok_or((StatusCode::FORBIDDEN, "invalid id"))?;
 
     // get compilation result with request id
-    let results = state.results.read().await;
-    results
-        .get(id as usize)
-        .map(|r| r.as_ref().map(|data| data.clone().into_vec()))
+    let data = state
+        .storage
+        .load_result(id)
+        .await
+        .map_err(|err| (storage_error(err), "failed to load data"))?
         .ok_or((StatusCode::NOT_FOUND, "no such data for that id"))?
-        .map_err(|_| (StatusCode::PRECONDITION_FAILED, "compile failed"))
+        .map_err(|_| (StatusCode::PRECONDITION_FAILED, "compile failed"))?;
+
+    let filename = format!("{}_v{}.res", task.filename, task.ver);
+    Ok(download::respond(headers, &data, &filename))
 }
 
 #[derive(Debug)]
@@ -492,65 +986,64 @@ where
     B::Data: Send,
     B::Error: Into<BoxError>,
 {
-    type Rejection = (StatusCode, &'static str);
+    type Rejection = (StatusCode, String);
 
     #[tracing::instrument(name = "UploadedFile", skip_all)]
     async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
-        let bytes = Bytes::from_request(req).await.unwrap();
-
-        // split bytes by number(0xc1) of space characters(0x20) as separator
-        let (mut p0, mut p1) = (None, None);
-        for (i, &b) in bytes.iter().enumerate() {
-            match (p0, b) {
-                (None, 0x20) => p0 = Some(i),
-                (None, _) => {}
-                (Some(_), 0x20) => {}
-                (Some(s), _) if i - s > 0xc0 => {
-                    p1 = Some(i - s);
-                    break;
-                }
-                (Some(_), _) => p0 = None,
-            }
-        }
-        let (p0, p1) = p0
-            .zip(p1)
-            .ok_or((StatusCode::BAD_REQUEST, "bad data format"))?;
-
-        let (filename, rest) = bytes.split_at(p0);
-        let (_pad, data) = rest.split_at(p1);
-
-        // get real name (`123` in `..\compileplatform\upload\123.res`)
-        let filename = filename
-            .rsplit(|b| *b == b'\\')
-            .next()
-            .and_then(|s| s.split(|b| *b == b'.').next())
-            .ok_or((StatusCode::BAD_REQUEST, "filename not found"))?
-            .to_owned();
-
-        tracing::debug!("filename = {:X?}", filename);
-
-        let mut buf = Vec::new();
-        crypto::decompress(data, &mut buf).map_err(|err| {
-            tracing::error!("decompress error: {:?}", err);
-            (StatusCode::INTERNAL_SERVER_ERROR, "decompress error")
+        let bytes = Bytes::from_request(req).await.map_err(|err| {
+            tracing::warn!("failed to read upload: {}", err);
+            (StatusCode::BAD_REQUEST, "failed to read upload".to_owned())
         })?;
 
-        let filename = String::from_utf8(filename)
-            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "unexpected encoding"))?;
+        let limit = req
+            .extensions()
+            .get::<Arc<SharedState>>()
+            .map_or(crypto::DEFAULT_LIMIT, |state| state.build.max_database_size);
+        let upload = upload::parse(&bytes, limit).map_err(|err| {
+            tracing::warn!("rejected upload: {}", err);
+            (StatusCode::BAD_REQUEST, err.to_string())
+        })?;
+
+        tracing::debug!("filename = {}", upload.filename);
 
         Ok(UploadedFile {
-            filename,
-            data: buf.into_boxed_slice(),
+            filename: upload.filename,
+            data: upload.data.into_boxed_slice(),
         })
     }
 }
 
 #[tracing::instrument]
-async fn upload(Extension(state): Extension<Arc<SharedState>>, file: UploadedFile) -> &'static str {
-    let mut files = state.files.write().await;
-    files.insert(file.filename, file.data);
+async fn upload(
+    Extension(state): Extension<Arc<SharedState>>,
+    file: UploadedFile,
+) -> Result<&'static str, Response> {
+    // reject broken databases early, warnings are reported once the game is built
+    let UploadedFile { filename, data } = file;
+    let (data, compiled) = tokio::task::spawn_blocking(move || {
+        let compiled = dream_tutor::compile_database(&data);
+        (data, compiled)
+    })
+    .await
+    .map_err(|err| {
+        tracing::error!("compile panicked: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    })?;
+    match compiled {
+        Ok(_) => tracing::debug!("{} compiled", filename),
+        Err(err) => {
+            tracing::info!("reject {}: {}", filename, err);
+            return Err((StatusCode::BAD_REQUEST, gbk_text(&fail_reason(&err))).into_response());
+        }
+    }
+
+    state
+        .storage
+        .store_file(&filename, data)
+        .await
+        .map_err(|err| storage_error(err).into_response())?;
 
-    "ok"
+    Ok("ok")
 }
 
 async fn avatar() -> &'static [u8] {
@@ -0,0 +1,406 @@
+//! Obfuscation of the database, making its strings and names harder to read from a game
+//!
+//! Neither of them is encryption in any real sense, anyone who runs the game can recover
+//! the strings, and the names are only gone for good if debug info is stripped.
+
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, fmt::Write, io};
+
+use crate::bytecode;
+
+/// Global holding the decrypted strings, unlikely to be used by any game
+const STRINGS: &str = "__dt_strings";
+
+/// Replace string literals by lookups into a table decrypted when the chunk is loaded
+///
+/// Line numbers are kept, the decryption is prepended to the first line. The strings are a
+/// global, so that they take neither one of the 200 locals of the chunk nor one of the 60
+/// upvalues of each function using them.
+///
+/// Fails with the line of the first string or comment `source` has which can not be lexed.
+pub fn encrypt_strings(source: &[u8]) -> Result<Vec<u8>, usize> {
+    let key = &Sha256::digest(source)[..16];
+
+    let mut lexer = Lexer {
+        src: source,
+        pos: 0,
+    };
+    let mut out = Vec::with_capacity(source.len());
+    let mut strings: Vec<Vec<u8>> = Vec::new();
+    let mut indices = HashMap::new();
+
+    // keep the shebang line as is, LuaJIT skips it
+    if source.starts_with(b"#") {
+        lexer.skip_line();
+        lexer.newline();
+        out.extend_from_slice(&source[..lexer.pos]);
+    }
+    let prelude_at = out.len();
+    // end of the last token, only whitespace and comments follow it in `out`
+    let mut token_end = prelude_at;
+
+    while let Some(c) = lexer.peek(0) {
+        let start = lexer.pos;
+        let unlexed = || count_lines(&source[..start]) + 1;
+        match c {
+            b'-' if lexer.peek(1) == Some(b'-') => {
+                lexer.pos += 2;
+                match lexer.long_bracket() {
+                    Some(level) => {
+                        lexer.long_string(level).ok_or_else(unlexed)?;
+                    }
+                    None => lexer.skip_line(),
+                }
+                out.extend_from_slice(&source[start..lexer.pos]);
+            }
+            b'"' | b'\'' | b'[' => {
+                let value = match c {
+                    b'[' => match lexer.long_bracket() {
+                        Some(level) => lexer.long_string(level).ok_or_else(unlexed)?,
+                        None => {
+                            lexer.pos += 1;
+                            out.push(c);
+                            token_end = out.len();
+                            continue;
+                        }
+                    },
+                    _ => lexer.short_string().ok_or_else(unlexed)?,
+                };
+
+                let next = strings.len() + 1;
+                let idx = *indices.entry(value.clone()).or_insert(next);
+                if idx == next {
+                    strings.push(value);
+                }
+
+                // `f\n"..."` is a call, while `f\n(...)` is ambiguous to LuaJIT,
+                // so move it up right after whatever is before it, comments included
+                let space = out.split_off(token_end);
+
+                // parentheses keep it an expression even as argument of `f"..."`
+                out.extend_from_slice(format!("({}[{}]", STRINGS, idx).as_bytes());
+                let lines = count_lines(&source[start..lexer.pos]);
+                out.resize(out.len() + lines, b'\n');
+                out.push(b')');
+                token_end = out.len();
+                out.extend(space);
+            }
+            _ => {
+                lexer.pos += 1;
+                out.push(c);
+                if !c.is_ascii_whitespace() {
+                    token_end = out.len();
+                }
+            }
+        }
+    }
+
+    if strings.is_empty() {
+        return Ok(source.to_owned());
+    }
+
+    let mut prelude = String::new();
+    write!(prelude, "do local k, s = \"{}\", {{", escape(key)).unwrap();
+    for (i, value) in strings.iter().enumerate() {
+        let encrypted: Vec<u8> = value
+            .iter()
+            .enumerate()
+            .map(|(j, b)| b ^ key[(i + 1 + j + 1) % key.len()])
+            .collect();
+        write!(prelude, "\"{}\",", escape(&encrypted)).unwrap();
+    }
+    write!(
+        prelude,
+        "}} for i = 1, #s do local e, o = s[i], {{}} for j = 1, #e do \
+         o[j] = string.char(bit.bxor(e:byte(j), k:byte((i + j) % #k + 1))) end \
+         s[i] = table.concat(o) end {} = s end ",
+        STRINGS
+    )
+    .unwrap();
+
+    out.splice(prelude_at..prelude_at, prelude.into_bytes());
+    Ok(out)
+}
+
+/// Give upvalues and local variables in debug info meaningless names of the same length
+pub fn rename_locals(bytecode: &mut [u8]) -> Result<(), io::Error> {
+    let dump = bytecode::parse(bytecode)?;
+
+    let mut renamed: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+    for range in dump.protos.iter().flat_map(|proto| &proto.names) {
+        let name = &bytecode[range.clone()];
+        let next = renamed.len();
+        let new = renamed
+            .entry(name.to_owned())
+            .or_insert_with(|| meaningless(next, name.len()))
+            .clone();
+        bytecode[range.clone()].copy_from_slice(&new);
+    }
+    Ok(())
+}
+
+/// `n` in letters, cut or padded to `len`
+fn meaningless(mut n: usize, len: usize) -> Vec<u8> {
+    let mut name = Vec::with_capacity(len);
+    while name.len() < len {
+        name.push(b'a' + (n % 26) as u8);
+        n /= 26;
+    }
+    name
+}
+
+fn escape(data: &[u8]) -> String {
+    let mut s = String::with_capacity(data.len() * 4);
+    for b in data {
+        write!(s, "\\{:03}", b).unwrap();
+    }
+    s
+}
+
+/// Line breaks as LuaJIT counts them, any of `\n`, `\r`, `\r\n` and `\n\r`
+fn count_lines(text: &[u8]) -> usize {
+    let mut lines = 0;
+    let mut i = 0;
+    while i < text.len() {
+        if let c @ (b'\n' | b'\r') = text[i] {
+            lines += 1;
+            if matches!(text.get(i + 1), Some(&n) if (n == b'\n' || n == b'\r') && n != c) {
+                i += 1;
+            }
+        }
+        i += 1;
+    }
+    lines
+}
+
+/// Just enough of the LuaJIT lexer to find strings and comments
+struct Lexer<'a> {
+    src: &'a [u8],
+    pos: usize,
+}
+
+impl Lexer<'_> {
+    fn peek(&self, n: usize) -> Option<u8> {
+        self.src.get(self.pos + n).copied()
+    }
+
+    fn skip_line(&mut self) {
+        while !matches!(self.peek(0), None | Some(b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    /// Skip `newline` sequence at the position, if any
+    fn newline(&mut self) -> bool {
+        match self.peek(0) {
+            Some(c @ (b'\n' | b'\r')) => {
+                self.pos += 1;
+                if matches!(self.peek(0), Some(n) if (n == b'\n' || n == b'\r') && n != c) {
+                    self.pos += 1;
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Level of a long bracket `[==[` opening at the position, consumed if so
+    fn long_bracket(&mut self) -> Option<usize> {
+        if self.peek(0) != Some(b'[') {
+            return None;
+        }
+        let mut level = 0;
+        while self.peek(1 + level) == Some(b'=') {
+            level += 1;
+        }
+        if self.peek(1 + level) != Some(b'[') {
+            return None;
+        }
+        self.pos += level + 2;
+        Some(level)
+    }
+
+    /// Rest of a long string or comment, after its opening bracket
+    fn long_string(&mut self, level: usize) -> Option<Vec<u8>> {
+        let mut value = Vec::new();
+        // a line break right after the opening bracket is not part of the string
+        self.newline();
+        loop {
+            match self.peek(0)? {
+                b']' if (1..=level).all(|i| self.peek(i) == Some(b'='))
+                    && self.peek(level + 1) == Some(b']') =>
+                {
+                    self.pos += level + 2;
+                    return Some(value);
+                }
+                b'\n' | b'\r' => {
+                    self.newline();
+                    value.push(b'\n');
+                }
+                c => {
+                    self.pos += 1;
+                    value.push(c);
+                }
+            }
+        }
+    }
+
+    /// Value of a quoted string starting at the position
+    fn short_string(&mut self) -> Option<Vec<u8>> {
+        let delim = self.peek(0)?;
+        self.pos += 1;
+
+        let mut value = Vec::new();
+        loop {
+            let c = self.peek(0)?;
+            self.pos += 1;
+            match c {
+                c if c == delim => return Some(value),
+                b'\n' | b'\r' => return None,
+                b'\\' => {
+                    let c = self.peek(0)?;
+                    match c {
+                        b'a' => value.push(7),
+                        b'b' => value.push(8),
+                        b'f' => value.push(12),
+                        b'n' => value.push(b'\n'),
+                        b'r' => value.push(b'\r'),
+                        b't' => value.push(b'\t'),
+                        b'v' => value.push(11),
+                        b'\\' | b'"' | b'\'' => value.push(c),
+                        b'x' => {
+                            let hex = self.src.get(self.pos + 1..self.pos + 3)?;
+                            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                                return None;
+                            }
+                            let hex = std::str::from_utf8(hex).ok()?;
+                            value.push(u8::from_str_radix(hex, 16).ok()?);
+                            self.pos += 2;
+                        }
+                        b'z' => {
+                            self.pos += 1;
+                            while let Some(c) = self.peek(0) {
+                                if c.is_ascii_whitespace() || c == 11 {
+                                    self.pos += 1;
+                                } else {
+                                    break;
+                                }
+                            }
+                            continue;
+                        }
+                        b'\n' | b'\r' => {
+                            self.newline();
+                            value.push(b'\n');
+                            continue;
+                        }
+                        b'0'..=b'9' => {
+                            let digits = self.src[self.pos..]
+                                .iter()
+                                .take(3)
+                                .take_while(|b| b.is_ascii_digit())
+                                .count();
+                            let n: u32 = std::str::from_utf8(&self.src[self.pos..][..digits])
+                                .ok()?
+                                .parse()
+                                .ok()?;
+                            value.push(u8::try_from(n).ok()?);
+                            self.pos += digits;
+                            continue;
+                        }
+                        _ => return None,
+                    }
+                    self.pos += 1;
+                }
+                c => value.push(c),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mlua::Lua;
+
+    use super::*;
+    use crate::lua;
+
+    fn run(source: &[u8]) -> (String, i64) {
+        let lua = unsafe { Lua::unsafe_new() };
+        lua.load(source).eval().unwrap()
+    }
+
+    #[test]
+    fn encrypt_strings_keeps_behavior() {
+        let source = br#"local t = {}
+t[ [[a
+b]] ] = 'x\65\z
+    y' -- "not a string"
+local s = t["a\nb"] .. "\x41" .. [==[]]]==] .. tostring
+"!"
+--[[ 'nor this' ]] return s, debug.getinfo(1, "l").currentline
+"#;
+        let obfuscated = encrypt_strings(source).unwrap();
+        assert_eq!(run(&obfuscated), run(source));
+        assert_eq!(run(source), ("xAyA]]!".to_owned(), 7));
+
+        let bytecode = lua::compile("database.lua", &obfuscated, true).unwrap();
+        assert!(bytecode.windows(3).all(|w| w != b"xAy"));
+    }
+
+    #[test]
+    fn encrypt_strings_after_comments() {
+        let source = b"local s = tostring(
+-- comment
+'a') .. tostring -- comment
+'b' local n = select('#', ...)
+return s .. n, debug.getinfo(1, 'l').currentline -- no line break after";
+        let obfuscated = encrypt_strings(source).unwrap();
+        assert_eq!(run(&obfuscated), run(source));
+        assert_eq!(run(source), ("ab0".to_owned(), 5));
+    }
+
+    #[test]
+    fn keep_locals_of_chunk() {
+        // as many locals as a function is allowed to have
+        let mut source: String = (0..200)
+            .map(|i| format!("local v{} = {}\n", i, i))
+            .collect();
+        source.push_str("return 'v' .. v199, v0");
+        let obfuscated = encrypt_strings(source.as_bytes()).unwrap();
+        assert_eq!(run(&obfuscated), ("v199".to_owned(), 0));
+    }
+
+    #[test]
+    fn keep_upvalues_of_closures() {
+        // as many upvalues as a function is allowed to have
+        let mut source: String = (0..60).map(|i| format!("local v{} = {}\n", i, i)).collect();
+        source.push_str("local function f() return 'v' .. (0");
+        for i in 0..60 {
+            source.push_str(&format!(" + v{}", i));
+        }
+        source.push_str(") end\nreturn f(), 0");
+        let obfuscated = encrypt_strings(source.as_bytes()).unwrap();
+        assert_eq!(run(&obfuscated), ("v1770".to_owned(), 0));
+    }
+
+    #[test]
+    fn fail_on_unknown_escapes() {
+        assert_eq!(encrypt_strings(b"local a = 1\nlocal s = '\\u{48}'"), Err(2));
+        assert_eq!(encrypt_strings(b"local s = [[\n\n"), Err(1));
+    }
+
+    #[test]
+    fn rename_locals_in_debug_info() {
+        if !crate::luajit_2_0() {
+            return;
+        }
+        let source = "local secret_name = 41 return function() return secret_name + 1 end";
+        let mut bytecode = lua::compile("database.lua", source, false).unwrap();
+        rename_locals(&mut bytecode).unwrap();
+        assert!(bytecode.windows(11).all(|w| w != b"secret_name"));
+
+        let lua = unsafe { Lua::unsafe_new() };
+        let f: mlua::Function = lua.load(&bytecode).eval().unwrap();
+        assert_eq!(f.call::<_, i64>(()).unwrap(), 42);
+    }
+}
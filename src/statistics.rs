@@ -0,0 +1,264 @@
+//! Launches reported by built games, which request the report url as they start
+//!
+//! Games report as the task which built them, with a token only its games carry, so reports of
+//! unknown builds are dropped. Anyone who extracts the url from a game can still report for it,
+//! counts are only as honest as players.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use axum::{extract::Query, routing::get, Extension, Json, Router};
+use axum_extra::extract::CookieJar;
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    check_session, now, session_uid, storage::Launches, storage_error, CompileTask, SharedState,
+};
+
+/// Routes for games to report launches and for their authors to look at them
+pub fn routes() -> Router {
+    Router::new()
+        .route("/report", get(report))
+        .route("/launches", get(launches))
+}
+
+#[derive(Debug, Deserialize)]
+struct Report {
+    gid: u32,
+    uid: u32,
+    token: String,
+}
+
+#[tracing::instrument(skip(token))]
+async fn report(
+    Extension(state): Extension<Arc<SharedState>>,
+    Query(Report { gid, uid, token }): Query<Report>,
+) -> Result<StatusCode, StatusCode> {
+    let tasks = state.storage.load_tasks(uid).await.map_err(storage_error)?;
+    match tasks.get(&gid) {
+        Some(task) if !task.report_token.is_empty() && task.report_token == token => {}
+        _ => return Err(StatusCode::NOT_FOUND),
+    }
+
+    state
+        .storage
+        .record_launch(gid, now().date())
+        .await
+        .map_err(storage_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+struct LaunchQuery {
+    filename: Option<String>,
+    ver: Option<u32>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct LaunchSummary {
+    builds: Vec<BuildLaunches>,
+    versions: Vec<VersionLaunches>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct BuildLaunches {
+    id: u32,
+    filename: String,
+    ver: u32,
+    days: Launches,
+    total: u64,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct VersionLaunches {
+    filename: String,
+    ver: u32,
+    days: Launches,
+    total: u64,
+}
+
+/// Launches of the games built by the logged in user, optionally of one game or version
+#[tracing::instrument]
+async fn launches(
+    Extension(state): Extension<Arc<SharedState>>,
+    jar: CookieJar,
+    Query(query): Query<LaunchQuery>,
+) -> Result<Json<LaunchSummary>, StatusCode> {
+    let session = check_session(&state.store, jar).await?;
+    let uid = session_uid(&session)?;
+
+    let mut tasks: Vec<_> = state
+        .storage
+        .load_tasks(uid)
+        .await
+        .map_err(storage_error)?
+        .into_values()
+        .filter(|task| query.filename.is_none() || query.filename.as_ref() == Some(&task.filename))
+        .filter(|task| query.ver.is_none() || query.ver == Some(task.ver))
+        .collect();
+    tasks.sort_by_key(|task| task.id);
+
+    let mut builds = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        let launches = state
+            .storage
+            .load_launches(task.id)
+            .await
+            .map_err(storage_error)?;
+        builds.push((task, launches));
+    }
+    Ok(Json(summarize(builds)))
+}
+
+/// Launches of each build, and of all builds of the same version
+fn summarize(builds: Vec<(CompileTask, Launches)>) -> LaunchSummary {
+    let mut versions: BTreeMap<(String, u32), Launches> = BTreeMap::new();
+    for (task, days) in &builds {
+        let version = versions
+            .entry((task.filename.clone(), task.ver))
+            .or_default();
+        for (day, count) in days {
+            *version.entry(*day).or_default() += count;
+        }
+    }
+
+    let total = |days: &Launches| days.values().sum();
+    LaunchSummary {
+        versions: versions
+            .into_iter()
+            .map(|((filename, ver), days)| VersionLaunches {
+                filename,
+                ver,
+                total: total(&days),
+                days,
+            })
+            .collect(),
+        builds: builds
+            .into_iter()
+            .map(|(task, days)| BuildLaunches {
+                id: task.id,
+                filename: task.filename,
+                ver: task.ver,
+                total: total(&days),
+                days,
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_session::{Session, SessionStore};
+    use axum::body::Body;
+    use hyper::Request;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{CompileStatus, GameType};
+
+    fn task(id: u32, ver: u32) -> CompileTask {
+        CompileTask {
+            id,
+            filename: "123".to_owned(),
+            addtime: now(),
+            status: CompileStatus::Done,
+            op_login: GameType::Offline,
+            op_qudong: false,
+            ver,
+            header: None,
+            warnings: Vec::new(),
+            report_token: format!("token{}", id),
+        }
+    }
+
+    async fn get(
+        state: &Arc<SharedState>,
+        uri: &str,
+        cookie: Option<&str>,
+    ) -> (StatusCode, Vec<u8>) {
+        let app = routes().layer(Extension(state.clone()));
+        let mut request = Request::get(uri);
+        if let Some(cookie) = cookie {
+            request = request.header("Cookie", format!("PHPSESSID={}", cookie));
+        }
+        let response = app
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, body.to_vec())
+    }
+
+    #[tokio::test]
+    async fn count_reported_launches() {
+        let state = SharedState::in_memory().await;
+        state.storage.store_task(1, task(0, 1)).await.unwrap();
+        state.storage.store_task(2, task(1, 1)).await.unwrap();
+
+        for _ in 0..2 {
+            let (status, _) = get(&state, "/report?gid=0&uid=1&token=token0", None).await;
+            assert_eq!(status, StatusCode::NO_CONTENT);
+        }
+        for uri in [
+            "/report?gid=0&uid=1&token=token1",
+            "/report?gid=0&uid=1&token=",
+            "/report?gid=0&uid=2&token=token0",
+            "/report?gid=2&uid=1&token=token2",
+        ] {
+            assert_eq!(
+                get(&state, uri, None).await.0,
+                StatusCode::NOT_FOUND,
+                "{}",
+                uri
+            );
+        }
+
+        let (status, _) = get(&state, "/launches", None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let mut session = Session::new();
+        session.insert("uid", 1).unwrap();
+        let cookie = state.store.store_session(session).await.unwrap().unwrap();
+        let cookie = base64::encode_config(cookie, base64::CRYPT);
+        let (status, body) = get(&state, "/launches?ver=1", Some(&cookie)).await;
+        assert_eq!(status, StatusCode::OK);
+        let summary: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(summary["builds"].as_array().unwrap().len(), 1);
+        assert_eq!(summary["builds"][0]["id"], 0);
+        assert_eq!(summary["builds"][0]["total"], 2);
+    }
+
+    #[test]
+    fn summarize_launches() {
+        let day = |d| time::Date::from_calendar_date(2022, time::Month::July, d).unwrap();
+
+        let summary = summarize(vec![
+            (task(1, 1), Launches::from([(day(1), 2), (day(2), 1)])),
+            (task(2, 1), Launches::from([(day(2), 3)])),
+            (task(3, 2), Launches::new()),
+        ]);
+        assert_eq!(summary.builds.len(), 3);
+        assert_eq!(summary.builds[0].total, 3);
+        assert_eq!(
+            summary.versions,
+            vec![
+                VersionLaunches {
+                    filename: "123".to_owned(),
+                    ver: 1,
+                    days: Launches::from([(day(1), 2), (day(2), 4)]),
+                    total: 6,
+                },
+                VersionLaunches {
+                    filename: "123".to_owned(),
+                    ver: 2,
+                    days: Launches::new(),
+                    total: 0,
+                },
+            ]
+        );
+
+        let json = serde_json::to_value(&summary.versions[0]).unwrap();
+        assert_eq!(json["days"]["2022-07-02"], 4);
+    }
+}
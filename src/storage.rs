@@ -0,0 +1,651 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use async_trait::async_trait;
+use tokio::{
+    fs,
+    sync::{Mutex, RwLock},
+};
+
+use crate::{CompileStatus, CompileTask};
+
+/// Packed artifact on success, reason on failure
+pub type CompileResult = Result<Box<[u8]>, String>;
+
+/// Named resources of an auto update game in the order they are bundled
+pub type UpdateFiles = Vec<(String, Box<[u8]>)>;
+
+/// Launches of a built game per day
+pub type Launches = BTreeMap<time::Date, u64>;
+
+/// Backend keeping uploaded game data, compilation results and task lists
+#[async_trait]
+pub trait Storage: Debug + Send + Sync {
+    async fn store_file(&self, name: &str, data: Box<[u8]>) -> io::Result<()>;
+
+    async fn load_file(&self, name: &str) -> io::Result<Option<Box<[u8]>>>;
+
+    /// Allocate an unused id for a new compilation task
+    async fn next_id(&self) -> io::Result<u32>;
+
+    async fn store_result(&self, id: u32, result: CompileResult) -> io::Result<()>;
+
+    async fn load_result(&self, id: u32) -> io::Result<Option<CompileResult>>;
+
+    /// Insert or replace the task with the same id owned by `uid`
+    async fn store_task(&self, uid: u32, task: CompileTask) -> io::Result<()>;
+
+    async fn load_tasks(&self, uid: u32) -> io::Result<HashMap<u32, CompileTask>>;
+
+    /// Replace resources of version `ver` of the auto update game `filename` owned by `uid`
+    async fn store_update(
+        &self,
+        uid: u32,
+        filename: &str,
+        ver: u32,
+        files: UpdateFiles,
+    ) -> io::Result<()>;
+
+    /// Load resources of version `ver`, or the latest version if `None`
+    async fn load_update(
+        &self,
+        uid: u32,
+        filename: &str,
+        ver: Option<u32>,
+    ) -> io::Result<Option<(u32, UpdateFiles)>>;
+
+    /// Count a launch of the game built by task `id` on `day`
+    async fn record_launch(&self, id: u32, day: time::Date) -> io::Result<()>;
+
+    async fn load_launches(&self, id: u32) -> io::Result<Launches>;
+}
+
+/// Keep everything in memory, all data is lost once the server stops
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    files: RwLock<HashMap<String, Box<[u8]>>>,
+    results: RwLock<HashMap<u32, CompileResult>>,
+    tasks: RwLock<HashMap<u32, HashMap<u32, CompileTask>>>,
+    updates: RwLock<HashMap<(u32, String), BTreeMap<u32, UpdateFiles>>>,
+    launches: RwLock<HashMap<u32, Launches>>,
+    counter: AtomicU32,
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn store_file(&self, name: &str, data: Box<[u8]>) -> io::Result<()> {
+        self.files.write().await.insert(name.to_owned(), data);
+        Ok(())
+    }
+
+    async fn load_file(&self, name: &str) -> io::Result<Option<Box<[u8]>>> {
+        Ok(self.files.read().await.get(name).cloned())
+    }
+
+    async fn next_id(&self) -> io::Result<u32> {
+        Ok(self.counter.fetch_add(1, Ordering::SeqCst))
+    }
+
+    async fn store_result(&self, id: u32, result: CompileResult) -> io::Result<()> {
+        self.results.write().await.insert(id, result);
+        Ok(())
+    }
+
+    async fn load_result(&self, id: u32) -> io::Result<Option<CompileResult>> {
+        Ok(self.results.read().await.get(&id).cloned())
+    }
+
+    async fn store_task(&self, uid: u32, task: CompileTask) -> io::Result<()> {
+        let mut tasks = self.tasks.write().await;
+        tasks.entry(uid).or_default().insert(task.id, task);
+        Ok(())
+    }
+
+    async fn load_tasks(&self, uid: u32) -> io::Result<HashMap<u32, CompileTask>> {
+        Ok(self
+            .tasks
+            .read()
+            .await
+            .get(&uid)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn store_update(
+        &self,
+        uid: u32,
+        filename: &str,
+        ver: u32,
+        files: UpdateFiles,
+    ) -> io::Result<()> {
+        let mut updates = self.updates.write().await;
+        updates
+            .entry((uid, filename.to_owned()))
+            .or_default()
+            .insert(ver, files);
+        Ok(())
+    }
+
+    async fn load_update(
+        &self,
+        uid: u32,
+        filename: &str,
+        ver: Option<u32>,
+    ) -> io::Result<Option<(u32, UpdateFiles)>> {
+        let updates = self.updates.read().await;
+        let versions = match updates.get(&(uid, filename.to_owned())) {
+            Some(versions) => versions,
+            None => return Ok(None),
+        };
+
+        let update = match ver {
+            Some(ver) => versions.get_key_value(&ver),
+            None => versions.iter().next_back(),
+        };
+        Ok(update.map(|(ver, files)| (*ver, files.clone())))
+    }
+
+    async fn record_launch(&self, id: u32, day: time::Date) -> io::Result<()> {
+        let mut launches = self.launches.write().await;
+        *launches.entry(id).or_default().entry(day).or_default() += 1;
+        Ok(())
+    }
+
+    async fn load_launches(&self, id: u32) -> io::Result<Launches> {
+        Ok(self
+            .launches
+            .read()
+            .await
+            .get(&id)
+            .cloned()
+            .unwrap_or_default())
+    }
+}
+
+/// Keep everything under a directory so that it survives restarts
+///
+/// ```text
+/// <root>/files/<name>.res             uploaded game data
+/// <root>/results/<id>.bin             packed artifact of a succeeded task
+/// <root>/results/<id>.err             reason of a failed task
+/// <root>/tasks/<uid>.json             tasks submitted by a user
+/// <root>/updates/<uid>/<name>/<ver>/  resources of an auto update game, named in `index.json`
+/// <root>/launches/<id>.json           launches per day of the game built by a task
+/// ```
+///
+/// Names other than ASCII letters, digits, `-` and `_` are hex encoded after a `~`.
+#[derive(Debug)]
+pub struct DiskStorage {
+    root: PathBuf,
+    counter: AtomicU32,
+    // serialize read-modify-write of task lists
+    tasks_lock: Mutex<()>,
+    // and of launch counts
+    launches_lock: Mutex<()>,
+}
+
+impl DiskStorage {
+    pub async fn open(root: impl Into<PathBuf>) -> io::Result<Self> {
+        let root = root.into();
+        for dir in ["files", "results", "tasks", "updates", "launches"] {
+            fs::create_dir_all(root.join(dir)).await?;
+        }
+
+        // continue numbering after the largest id ever handed out
+        let mut next = 0;
+
+        let mut entries = fs::read_dir(root.join("tasks")).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().and_then(|s| s.to_str()) != Some("json") {
+                continue;
+            }
+
+            let mut tasks = read_tasks(&path).await?;
+            if let Some(max) = tasks.keys().max() {
+                next = next.max(max + 1);
+            }
+
+            // builds in progress were lost along with the previous process
+            let mut interrupted = false;
+            for task in tasks.values_mut() {
+                if matches!(task.status, CompileStatus::Processing) {
+                    let reason = "编译完成前服务器已停止，请重新提交";
+                    let err = root.join("results").join(format!("{}.err", task.id));
+                    write_atomic(&err, reason.as_bytes()).await?;
+                    task.status = CompileStatus::Failed;
+                    interrupted = true;
+                }
+            }
+            if interrupted {
+                let json = serde_json::to_vec(&tasks).map_err(io::Error::from)?;
+                write_atomic(&path, &json).await?;
+            }
+        }
+
+        let mut entries = fs::read_dir(root.join("results")).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let id = entry
+                .path()
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u32>().ok());
+            if let Some(id) = id {
+                next = next.max(id + 1);
+            }
+        }
+
+        tracing::info!("storage opened at {:?}, next id = {}", root, next);
+
+        Ok(Self {
+            root,
+            counter: AtomicU32::new(next),
+            tasks_lock: Mutex::new(()),
+            launches_lock: Mutex::new(()),
+        })
+    }
+
+    fn file_path(&self, name: &str) -> io::Result<PathBuf> {
+        let key = name_key(name)?;
+        Ok(self.root.join("files").join(format!("{key}.res")))
+    }
+
+    fn update_path(&self, uid: u32, filename: &str) -> io::Result<PathBuf> {
+        let key = name_key(filename)?;
+        Ok(self.root.join("updates").join(uid.to_string()).join(&*key))
+    }
+
+    fn result_path(&self, id: u32, ext: &str) -> PathBuf {
+        self.root.join("results").join(format!("{id}.{ext}"))
+    }
+
+    fn tasks_path(&self, uid: u32) -> PathBuf {
+        self.root.join("tasks").join(format!("{uid}.json"))
+    }
+
+    fn launches_path(&self, id: u32) -> PathBuf {
+        self.root.join("launches").join(format!("{id}.json"))
+    }
+}
+
+#[async_trait]
+impl Storage for DiskStorage {
+    async fn store_file(&self, name: &str, data: Box<[u8]>) -> io::Result<()> {
+        write_atomic(&self.file_path(name)?, &data).await
+    }
+
+    async fn load_file(&self, name: &str) -> io::Result<Option<Box<[u8]>>> {
+        let data = not_found_as_none(fs::read(self.file_path(name)?).await)?;
+        Ok(data.map(Vec::into_boxed_slice))
+    }
+
+    async fn next_id(&self) -> io::Result<u32> {
+        Ok(self.counter.fetch_add(1, Ordering::SeqCst))
+    }
+
+    async fn store_result(&self, id: u32, result: CompileResult) -> io::Result<()> {
+        // drop the outcome of a previous attempt with the same id
+        let stale = match &result {
+            Ok(data) => {
+                write_atomic(&self.result_path(id, "bin"), data).await?;
+                self.result_path(id, "err")
+            }
+            Err(reason) => {
+                write_atomic(&self.result_path(id, "err"), reason.as_bytes()).await?;
+                self.result_path(id, "bin")
+            }
+        };
+
+        not_found_as_none(fs::remove_file(stale).await).map(|_| ())
+    }
+
+    async fn load_result(&self, id: u32) -> io::Result<Option<CompileResult>> {
+        if let Some(data) = not_found_as_none(fs::read(self.result_path(id, "bin")).await)? {
+            return Ok(Some(Ok(data.into_boxed_slice())));
+        }
+
+        let reason = not_found_as_none(fs::read(self.result_path(id, "err")).await)?;
+        Ok(reason.map(|reason| Err(String::from_utf8_lossy(&reason).into_owned())))
+    }
+
+    async fn store_task(&self, uid: u32, task: CompileTask) -> io::Result<()> {
+        let _guard = self.tasks_lock.lock().await;
+
+        let path = self.tasks_path(uid);
+        let mut tasks = read_tasks(&path).await?;
+        tasks.insert(task.id, task);
+
+        let json = serde_json::to_vec(&tasks).map_err(io::Error::from)?;
+        write_atomic(&path, &json).await
+    }
+
+    async fn load_tasks(&self, uid: u32) -> io::Result<HashMap<u32, CompileTask>> {
+        read_tasks(&self.tasks_path(uid)).await
+    }
+
+    async fn store_update(
+        &self,
+        uid: u32,
+        filename: &str,
+        ver: u32,
+        files: UpdateFiles,
+    ) -> io::Result<()> {
+        // resource names are not path safe, so store them by position
+        let dir = self.update_path(uid, filename)?.join(ver.to_string());
+        fs::create_dir_all(&dir).await?;
+
+        let mut index = Vec::with_capacity(files.len());
+        for (i, (name, data)) in files.into_iter().enumerate() {
+            write_atomic(&dir.join(i.to_string()), &data).await?;
+            index.push(name);
+        }
+
+        let json = serde_json::to_vec(&index).map_err(io::Error::from)?;
+        write_atomic(&dir.join("index.json"), &json).await
+    }
+
+    async fn load_update(
+        &self,
+        uid: u32,
+        filename: &str,
+        ver: Option<u32>,
+    ) -> io::Result<Option<(u32, UpdateFiles)>> {
+        let dir = self.update_path(uid, filename)?;
+
+        let ver = match ver {
+            Some(ver) => ver,
+            None => {
+                let mut latest = None;
+                let mut entries = match not_found_as_none(fs::read_dir(&dir).await)? {
+                    Some(entries) => entries,
+                    None => return Ok(None),
+                };
+                while let Some(entry) = entries.next_entry().await? {
+                    let ver = entry
+                        .file_name()
+                        .to_str()
+                        .and_then(|s| s.parse::<u32>().ok());
+                    latest = latest.max(ver);
+                }
+                match latest {
+                    Some(ver) => ver,
+                    None => return Ok(None),
+                }
+            }
+        };
+
+        let dir = dir.join(ver.to_string());
+        let index: Vec<String> = match not_found_as_none(fs::read(dir.join("index.json")).await)? {
+            Some(json) => serde_json::from_slice(&json).map_err(io::Error::from)?,
+            None => return Ok(None),
+        };
+
+        let mut files = Vec::with_capacity(index.len());
+        for (i, name) in index.into_iter().enumerate() {
+            let data = fs::read(dir.join(i.to_string())).await?;
+            files.push((name, data.into_boxed_slice()));
+        }
+
+        Ok(Some((ver, files)))
+    }
+
+    async fn record_launch(&self, id: u32, day: time::Date) -> io::Result<()> {
+        let _guard = self.launches_lock.lock().await;
+
+        let path = self.launches_path(id);
+        let mut launches = read_launches(&path).await?;
+        *launches.entry(day).or_default() += 1;
+
+        let json = serde_json::to_vec(&launches).map_err(io::Error::from)?;
+        write_atomic(&path, &json).await
+    }
+
+    async fn load_launches(&self, id: u32) -> io::Result<Launches> {
+        read_launches(&self.launches_path(id)).await
+    }
+}
+
+/// Names become part of a path, those outside a conservative charset are hex encoded after a
+/// `~`, which names kept as they are never have
+fn name_key(name: &str) -> io::Result<Cow<'_, str>> {
+    if name.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "empty file name",
+        ));
+    }
+
+    let safe = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if safe {
+        Ok(Cow::Borrowed(name))
+    } else {
+        Ok(Cow::Owned(format!("~{}", hex::encode(name))))
+    }
+}
+
+async fn read_tasks(path: &Path) -> io::Result<HashMap<u32, CompileTask>> {
+    match not_found_as_none(fs::read(path).await)? {
+        Some(json) => serde_json::from_slice(&json).map_err(io::Error::from),
+        None => Ok(HashMap::new()),
+    }
+}
+
+async fn read_launches(path: &Path) -> io::Result<Launches> {
+    match not_found_as_none(fs::read(path).await)? {
+        Some(json) => serde_json::from_slice(&json).map_err(io::Error::from),
+        None => Ok(Launches::new()),
+    }
+}
+
+/// Write into a temporary file first so that a crash never leaves a half-written file behind
+///
+/// Every write has a temporary file of its own, so concurrent writes to one path never mix.
+async fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    static WRITES: AtomicU32 = AtomicU32::new(0);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let write = WRITES.fetch_add(1, Ordering::Relaxed);
+    let tmp = path.with_file_name(format!(".{}.{}-{}.tmp", name, std::process::id(), write));
+    fs::write(&tmp, data).await?;
+    if let Err(err) = fs::rename(&tmp, path).await {
+        let _ = fs::remove_file(&tmp).await;
+        return Err(err);
+    }
+    Ok(())
+}
+
+fn not_found_as_none<T>(result: io::Result<T>) -> io::Result<Option<T>> {
+    match result {
+        Ok(v) => Ok(Some(v)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GameType;
+
+    fn task(id: u32) -> CompileTask {
+        CompileTask {
+            id,
+            filename: "123".to_owned(),
+            addtime: time::PrimitiveDateTime::new(
+                time::Date::from_calendar_date(2022, time::Month::July, 1).unwrap(),
+                time::Time::MIDNIGHT,
+            ),
+            status: CompileStatus::Done,
+            op_login: GameType::Offline,
+            op_qudong: false,
+            ver: 1,
+            header: None,
+            warnings: Vec::new(),
+            report_token: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn disk_storage_survives_reopen() {
+        let root = std::env::temp_dir().join(format!("dream-tutor-{}", uuid::Uuid::new_v4()));
+
+        {
+            let storage = DiskStorage::open(&root).await.unwrap();
+            storage
+                .store_file("123", b"game".to_vec().into_boxed_slice())
+                .await
+                .unwrap();
+            let id = storage.next_id().await.unwrap();
+            storage
+                .store_result(id, Ok(b"packed".to_vec().into_boxed_slice()))
+                .await
+                .unwrap();
+            storage.store_task(1, task(id)).await.unwrap();
+        }
+
+        let storage = DiskStorage::open(&root).await.unwrap();
+        assert_eq!(storage.next_id().await.unwrap(), 1);
+        assert_eq!(
+            storage.load_file("123").await.unwrap().as_deref(),
+            Some(&b"game"[..])
+        );
+        assert_eq!(
+            storage.load_result(0).await.unwrap(),
+            Some(Ok(b"packed".to_vec().into_boxed_slice()))
+        );
+        assert!(storage.load_tasks(1).await.unwrap().contains_key(&0));
+        // names unsafe in paths are kept apart from names which are not
+        assert_eq!(storage.load_file("../123").await.unwrap(), None);
+        storage
+            .store_file("新游戏", b"chinese".to_vec().into_boxed_slice())
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.load_file("新游戏").await.unwrap().as_deref(),
+            Some(&b"chinese"[..])
+        );
+        assert!(root.join("files/~e696b0e6b8b8e6888f.res").exists());
+        assert!(storage.load_file("").await.is_err());
+
+        let day = time::Date::from_calendar_date(2022, time::Month::July, 2).unwrap();
+        storage.record_launch(0, day).await.unwrap();
+        storage.record_launch(0, day).await.unwrap();
+        assert_eq!(
+            storage.load_launches(0).await.unwrap(),
+            Launches::from([(day, 2)])
+        );
+        assert!(storage.load_launches(1).await.unwrap().is_empty());
+
+        fs::remove_dir_all(&root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn write_concurrently() {
+        let root = std::env::temp_dir().join(format!("dream-tutor-{}", uuid::Uuid::new_v4()));
+        let storage = std::sync::Arc::new(DiskStorage::open(&root).await.unwrap());
+
+        // uploads of one name at once each land whole
+        let writes = (0..16u8).map(|i| {
+            let storage = storage.clone();
+            tokio::spawn(async move {
+                let data = vec![i; 1 << 16].into_boxed_slice();
+                storage.store_file("123", data).await.unwrap();
+            })
+        });
+        for write in writes.collect::<Vec<_>>() {
+            write.await.unwrap();
+        }
+        let data = storage.load_file("123").await.unwrap().unwrap();
+        assert_eq!(data.len(), 1 << 16);
+        assert!(data.iter().all(|&b| b == data[0]));
+        let mut entries = fs::read_dir(root.join("files")).await.unwrap();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            assert!(!entry.file_name().to_string_lossy().ends_with(".tmp"));
+        }
+
+        fs::remove_dir_all(&root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn fail_interrupted_tasks() {
+        let root = std::env::temp_dir().join(format!("dream-tutor-{}", uuid::Uuid::new_v4()));
+
+        {
+            let storage = DiskStorage::open(&root).await.unwrap();
+            let mut processing = task(storage.next_id().await.unwrap());
+            processing.status = CompileStatus::Processing;
+            storage.store_task(1, processing).await.unwrap();
+            let done = task(storage.next_id().await.unwrap());
+            storage
+                .store_result(done.id, Ok(b"packed".to_vec().into_boxed_slice()))
+                .await
+                .unwrap();
+            storage.store_task(1, done).await.unwrap();
+        }
+
+        let storage = DiskStorage::open(&root).await.unwrap();
+        let tasks = storage.load_tasks(1).await.unwrap();
+        assert!(matches!(tasks[&0].status, CompileStatus::Failed));
+        assert!(matches!(
+            storage.load_result(0).await.unwrap(),
+            Some(Err(_))
+        ));
+        assert!(matches!(tasks[&1].status, CompileStatus::Done));
+        assert!(matches!(storage.load_result(1).await.unwrap(), Some(Ok(_))));
+
+        fs::remove_dir_all(&root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn keep_updates_per_owner() {
+        let root = std::env::temp_dir().join(format!("dream-tutor-{}", uuid::Uuid::new_v4()));
+        let backends: [Box<dyn Storage>; 2] = [
+            Box::new(MemoryStorage::default()),
+            Box::new(DiskStorage::open(&root).await.unwrap()),
+        ];
+        let files = |data: &[u8]| vec![("启动.lua".to_owned(), data.to_vec().into_boxed_slice())];
+
+        for storage in backends {
+            assert_eq!(storage.load_update(1, "新游戏", None).await.unwrap(), None);
+            storage
+                .store_update(1, "新游戏", 1, files(b"v1"))
+                .await
+                .unwrap();
+            storage
+                .store_update(1, "新游戏", 3, files(b"v3"))
+                .await
+                .unwrap();
+            storage
+                .store_update(2, "新游戏", 2, files(b"other"))
+                .await
+                .unwrap();
+
+            assert_eq!(
+                storage.load_update(1, "新游戏", None).await.unwrap(),
+                Some((3, files(b"v3")))
+            );
+            assert_eq!(
+                storage.load_update(1, "新游戏", Some(1)).await.unwrap(),
+                Some((1, files(b"v1")))
+            );
+            assert_eq!(
+                storage.load_update(1, "新游戏", Some(2)).await.unwrap(),
+                None
+            );
+            assert_eq!(
+                storage.load_update(2, "新游戏", None).await.unwrap(),
+                Some((2, files(b"other")))
+            );
+            assert_eq!(storage.load_update(3, "新游戏", None).await.unwrap(), None);
+        }
+
+        fs::remove_dir_all(&root).await.unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+//! Build tasks as the client lists them, and the details it has no place for
+//!
+//! The client parses the task list with fields of its own, so fields tasks gained since are kept
+//! out of the list and served from here to logged in authors instead.
+
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{extract::Path, routing::get, Extension, Json, Router};
+use axum_extra::extract::CookieJar;
+use hyper::StatusCode;
+use serde::Serialize;
+
+use crate::{
+    check_session, no_sub_second, num_bool, session_uid, storage_error, CompileStatus, CompileTask,
+    GameType, PluginHeader, SharedState,
+};
+
+/// Routes for authors to look at their build tasks
+pub fn routes() -> Router {
+    Router::new().route("/:id", get(details))
+}
+
+/// A task with only the fields the client knows
+#[derive(Debug, Serialize)]
+struct ListedTask<'a> {
+    id: u32,
+    filename: &'a str,
+    #[serde(with = "no_sub_second")]
+    addtime: time::PrimitiveDateTime,
+    status: &'a CompileStatus,
+    op_login: &'a GameType,
+    #[serde(with = "num_bool")]
+    op_qudong: bool,
+    ver: u32,
+}
+
+impl<'a> From<&'a CompileTask> for ListedTask<'a> {
+    fn from(task: &'a CompileTask) -> Self {
+        ListedTask {
+            id: task.id,
+            filename: &task.filename,
+            addtime: task.addtime,
+            status: &task.status,
+            op_login: &task.op_login,
+            op_qudong: task.op_qudong,
+            ver: task.ver,
+        }
+    }
+}
+
+/// Task list of the client, keyed by task id
+pub fn list(tasks: &HashMap<u32, CompileTask>) -> serde_json::Result<String> {
+    let listed: HashMap<_, _> = tasks
+        .iter()
+        .map(|(id, task)| (id, ListedTask::from(task)))
+        .collect();
+    serde_json::to_string(&listed)
+}
+
+#[derive(Debug, Serialize)]
+struct TaskDetails {
+    id: u32,
+    filename: String,
+    ver: u32,
+    header: Option<PluginHeader>,
+    warnings: Vec<String>,
+}
+
+/// Plugin info and warnings of a build task of the logged in user
+#[tracing::instrument]
+async fn details(
+    Extension(state): Extension<Arc<SharedState>>,
+    jar: CookieJar,
+    Path(id): Path<u32>,
+) -> Result<Json<TaskDetails>, StatusCode> {
+    let session = check_session(&state.store, jar).await?;
+    let uid = session_uid(&session)?;
+
+    let task = state
+        .storage
+        .load_tasks(uid)
+        .await
+        .map_err(storage_error)?
+        .remove(&id)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(TaskDetails {
+        id: task.id,
+        filename: task.filename,
+        ver: task.ver,
+        header: task.header,
+        warnings: task.warnings,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use async_session::{Session, SessionStore};
+    use axum::body::Body;
+    use hyper::Request;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::now;
+
+    fn task(id: u32) -> CompileTask {
+        CompileTask {
+            id,
+            filename: "123".to_owned(),
+            addtime: now(),
+            status: CompileStatus::Done,
+            op_login: GameType::Offline,
+            op_qudong: true,
+            ver: 2,
+            header: None,
+            warnings: vec!["未定义的变量 a".to_owned()],
+            report_token: "token".to_owned(),
+        }
+    }
+
+    #[test]
+    fn list_known_fields() {
+        let tasks = HashMap::from([(3, task(3))]);
+        let listed: serde_json::Value = serde_json::from_str(&list(&tasks).unwrap()).unwrap();
+        let listed = listed["3"].as_object().unwrap();
+        let mut fields: Vec<_> = listed.keys().map(String::as_str).collect();
+        fields.sort_unstable();
+        assert_eq!(
+            fields,
+            [
+                "addtime",
+                "filename",
+                "id",
+                "op_login",
+                "op_qudong",
+                "status",
+                "ver"
+            ]
+        );
+        assert_eq!(listed["op_qudong"], 1);
+        assert_eq!(listed["status"], 2);
+    }
+
+    #[tokio::test]
+    async fn show_details_to_owner() {
+        let state = SharedState::in_memory().await;
+        state.storage.store_task(1, task(0)).await.unwrap();
+
+        let mut session = Session::new();
+        session.insert("uid", 1).unwrap();
+        let cookie = state.store.store_session(session).await.unwrap().unwrap();
+        let cookie = base64::encode_config(cookie, base64::CRYPT);
+
+        let get = |uri: &'static str, cookie: Option<String>| {
+            let app = routes().layer(Extension(state.clone()));
+            async move {
+                let mut request = Request::get(uri);
+                if let Some(cookie) = cookie {
+                    request = request.header("Cookie", format!("PHPSESSID={}", cookie));
+                }
+                let response = app
+                    .oneshot(request.body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                let status = response.status();
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                (status, body)
+            }
+        };
+
+        assert_eq!(get("/0", None).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            get("/1", Some(cookie.clone())).await.0,
+            StatusCode::NOT_FOUND
+        );
+
+        let (status, body) = get("/0", Some(cookie)).await;
+        assert_eq!(status, StatusCode::OK);
+        let details: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(details["warnings"][0], "未定义的变量 a");
+        assert!(details.get("report_token").is_none());
+    }
+}
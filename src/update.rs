@@ -0,0 +1,179 @@
+use std::sync::Arc;
+
+use axum::{extract::Path, response::Response, routing::get, Extension, Json, Router};
+use hyper::{HeaderMap, StatusCode};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    download::{percent_encode, respond},
+    storage_error, SharedState,
+};
+
+/// Routes for auto update games to fetch their latest resources, as published by their owner
+pub fn routes() -> Router {
+    Router::new()
+        .route("/:uid/:filename/manifest", get(manifest))
+        .route("/:uid/:filename/:ver/:name", get(download))
+}
+
+/// Where auto update games of `filename` published by user `uid` find their resources
+pub fn update_url(public_url: &str, uid: u32, filename: &str) -> String {
+    format!(
+        "{}/dmupdate/{}/{}",
+        public_url.trim_end_matches('/'),
+        uid,
+        percent_encode(filename)
+    )
+}
+
+#[derive(Debug, Serialize)]
+struct Manifest {
+    filename: String,
+    ver: u32,
+    files: Vec<ManifestFile>,
+}
+
+#[derive(Debug, Serialize)]
+struct ManifestFile {
+    name: String,
+    size: usize,
+    sha256: String,
+}
+
+#[tracing::instrument]
+async fn manifest(
+    Extension(state): Extension<Arc<SharedState>>,
+    Path((uid, filename)): Path<(u32, String)>,
+) -> Result<Json<Manifest>, StatusCode> {
+    let (ver, files) = state
+        .storage
+        .load_update(uid, &filename, None)
+        .await
+        .map_err(storage_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let files = files
+        .into_iter()
+        .map(|(name, data)| ManifestFile {
+            name,
+            size: data.len(),
+            sha256: hex::encode(Sha256::digest(&data)),
+        })
+        .collect();
+
+    Ok(Json(Manifest {
+        filename,
+        ver,
+        files,
+    }))
+}
+
+#[tracing::instrument]
+async fn download(
+    Extension(state): Extension<Arc<SharedState>>,
+    Path((uid, filename, ver, name)): Path<(u32, String, u32, String)>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let (_, files) = state
+        .storage
+        .load_update(uid, &filename, Some(ver))
+        .await
+        .map_err(storage_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    files
+        .into_iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, data)| respond(&headers, &data, &name))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use hyper::Request;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn get(state: &Arc<SharedState>, uri: &str) -> (StatusCode, Vec<u8>) {
+        let app = routes().layer(Extension(state.clone()));
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, body.to_vec())
+    }
+
+    #[tokio::test]
+    async fn serve_latest_update() {
+        let state = SharedState::in_memory().await;
+        let files = |data: &[u8]| vec![("a.lua".to_owned(), data.to_vec().into_boxed_slice())];
+        state
+            .storage
+            .store_update(1, "123", 1, files(b"old"))
+            .await
+            .unwrap();
+        state
+            .storage
+            .store_update(1, "123", 2, files(b"new"))
+            .await
+            .unwrap();
+
+        let (status, body) = get(&state, "/1/123/manifest").await;
+        assert_eq!(status, StatusCode::OK);
+        let manifest: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(manifest["ver"], 2);
+        assert_eq!(manifest["files"][0]["name"], "a.lua");
+        assert_eq!(manifest["files"][0]["size"], 3);
+        assert_eq!(
+            manifest["files"][0]["sha256"],
+            hex::encode(Sha256::digest(b"new"))
+        );
+
+        assert_eq!(
+            get(&state, "/1/123/1/a.lua").await,
+            (StatusCode::OK, b"old".to_vec())
+        );
+        for uri in [
+            // games of the same name published by others
+            "/2/123/manifest",
+            "/2/123/1/a.lua",
+            "/1/456/manifest",
+            "/1/123/3/a.lua",
+            "/1/123/1/b.lua",
+            "/1/%2E%2E/manifest",
+        ] {
+            assert_eq!(get(&state, uri).await.0, StatusCode::NOT_FOUND, "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn serve_updates_by_url() {
+        let state = SharedState::in_memory().await;
+        let files = vec![("a.lua".to_owned(), b"new".to_vec().into_boxed_slice())];
+        state
+            .storage
+            .store_update(1, "新 游戏", 1, files)
+            .await
+            .unwrap();
+
+        // the url shipped in games has to be valid, whatever the name of the game is
+        let url = update_url("http://10.0.0.1/", 1, "新 游戏");
+        assert_eq!(
+            url,
+            "http://10.0.0.1/dmupdate/1/%E6%96%B0%20%E6%B8%B8%E6%88%8F"
+        );
+        let uri: hyper::Uri = url.parse().unwrap();
+        let path = uri.path().strip_prefix("/dmupdate").unwrap();
+        assert_eq!(
+            get(&state, &format!("{}/manifest", path)).await.0,
+            StatusCode::OK
+        );
+        assert_eq!(
+            get(&state, &format!("{}/1/a.lua", path)).await,
+            (StatusCode::OK, b"new".to_vec())
+        );
+    }
+}
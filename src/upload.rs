@@ -0,0 +1,217 @@
+//! Games uploaded by DreamMaker
+//!
+//! The body is the path of the database on the uploading machine, padded with spaces into a
+//! field of fixed size, then the compressed database:
+//!
+//! ```text
+//! ..\compileplatform\upload\123.res<at least 0xC1 spaces><compressed container>
+//! ```
+
+use std::{error, fmt, io};
+
+use dream_tutor::crypto;
+
+/// Spaces padding the path, shorter runs are part of it
+const MIN_PADDING: usize = 0xC1;
+/// Longest path accepted, `MAX_PATH` of Windows
+const MAX_PATH: usize = 260;
+/// Longest name of a game in bytes, storage hex encodes names beyond ASCII into file names
+const MAX_NAME: usize = 64;
+
+#[derive(Debug)]
+pub enum UploadError {
+    /// No padding separating the path from the database
+    MissingSeparator,
+    PathTooLong,
+    /// Path is not valid UTF-8
+    InvalidPath,
+    /// Name taken from the path is empty, too long or has characters unsafe in file names
+    InvalidName(String),
+    Compression(io::Error),
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UploadError::MissingSeparator => write!(f, "bad data format"),
+            UploadError::PathTooLong => write!(f, "path longer than {} bytes", MAX_PATH),
+            UploadError::InvalidPath => write!(f, "unexpected encoding of path"),
+            UploadError::InvalidName(name) => write!(f, "invalid filename {:?}", name),
+            UploadError::Compression(err) => write!(f, "decompress error: {}", err),
+        }
+    }
+}
+
+impl error::Error for UploadError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            UploadError::Compression(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Upload {
+    /// Name of the database file, without directories and extension
+    pub filename: String,
+    /// Decompressed database
+    pub data: Vec<u8>,
+}
+
+/// Parse `body`, rejecting databases larger than `limit` once decompressed
+pub fn parse(body: &[u8], limit: u64) -> Result<Upload, UploadError> {
+    let (path, compressed) = split(body)?;
+    let path = std::str::from_utf8(path).map_err(|_| UploadError::InvalidPath)?;
+    let filename = filename(path)?;
+
+    let mut data = Vec::new();
+    crypto::decompress_with_limit(compressed, &mut data, limit)
+        .map_err(UploadError::Compression)?;
+
+    Ok(Upload { filename, data })
+}
+
+/// Path and data around the first run of at least `MIN_PADDING` spaces
+fn split(body: &[u8]) -> Result<(&[u8], &[u8]), UploadError> {
+    let mut run = None;
+    for (i, &b) in body.iter().enumerate() {
+        match (b, run) {
+            (b' ', None) => run = Some(i),
+            (b' ', Some(_)) => {}
+            (_, Some(start)) if i - start >= MIN_PADDING => {
+                return Ok((&body[..start], &body[i..]));
+            }
+            _ => {
+                // no need to look through the whole database for the end of the path
+                if i >= MAX_PATH {
+                    return Err(UploadError::PathTooLong);
+                }
+                run = None;
+            }
+        }
+    }
+    Err(UploadError::MissingSeparator)
+}
+
+/// `123` of `..\compileplatform\upload\123.res`, safe to be used as a file name
+fn filename(path: &str) -> Result<String, UploadError> {
+    let name = path.rsplit(['\\', '/']).next().unwrap_or_default();
+    let name = name.split('.').next().unwrap_or_default();
+
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(UploadError::InvalidName(name.to_owned()));
+    }
+    Ok(name.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn body(path: &str, padding: usize, database: &[u8]) -> Vec<u8> {
+        let mut body = path.as_bytes().to_owned();
+        body.resize(body.len() + padding, b' ');
+        crypto::compress(database, &mut body).unwrap();
+        body
+    }
+
+    #[test]
+    fn reject_malformed_uploads() {
+        let upload = parse(
+            &body("..\\compileplatform\\upload\\123.res", 0xC1, b"db"),
+            100,
+        );
+        assert_eq!(
+            upload.unwrap(),
+            Upload {
+                filename: "123".to_owned(),
+                data: b"db".to_vec(),
+            }
+        );
+        // spaces in the path are fine as long as they are shorter than the padding
+        let upload = parse(&body("C:\\My Games\\游戏 1\\新游戏.res", 0x100, b"db"), 100);
+        assert_eq!(upload.unwrap().filename, "新游戏");
+
+        let cases = [
+            (b"".to_vec(), "MissingSeparator"),
+            (vec![b' '; 0x200], "MissingSeparator"),
+            (b"123.res\xff".to_vec(), "MissingSeparator"),
+            (body("123.res", 0xC0, b"db"), "MissingSeparator"),
+            (body(&"a".repeat(300), 0xC1, b"db"), "PathTooLong"),
+            (body("..\\..", 0xC1, b"db"), "InvalidName"),
+            (body("upload\\.res", 0xC1, b"db"), "InvalidName"),
+            (body("upload\\a:b.res", 0xC1, b"db"), "InvalidName"),
+            (
+                body(&format!("{}.res", "游".repeat(22)), 0xC1, b"db"),
+                "InvalidName",
+            ),
+            (body("upload\\123.res", 0xC1, &[0; 101]), "Compression"),
+        ];
+        for (body, expected) in cases {
+            let err = format!("{:?}", parse(&body, 100).unwrap_err());
+            assert!(
+                err.starts_with(expected),
+                "{} expected, got {}",
+                expected,
+                err
+            );
+        }
+
+        let mut trailing = body("123.res", 0xC1, b"db");
+        trailing.extend_from_slice(b"garbage");
+        assert!(matches!(
+            parse(&trailing, 100),
+            Err(UploadError::Compression(_))
+        ));
+
+        let mut invalid = body("123.res", 0xC1, b"db");
+        invalid[0] = 0xff;
+        assert!(matches!(
+            parse(&invalid, 100),
+            Err(UploadError::InvalidPath)
+        ));
+    }
+
+    proptest! {
+        #[test]
+        fn parse_any_body(body in proptest::collection::vec(any::<u8>(), 0..1024)) {
+            let _ = parse(&body, 1024);
+        }
+
+        #[test]
+        fn parse_padded_body(
+            body in proptest::collection::vec(prop_oneof![Just(b' '), any::<u8>()], 0..1024),
+        ) {
+            let _ = parse(&body, 1024);
+        }
+
+        #[test]
+        fn parse_valid_upload(
+            dirs in proptest::collection::vec("[^\\\\/\\x00]{0,16}", 0..4),
+            name in "[a-zA-Z0-9_\\-]{1,64}",
+            ext in "(\\.[a-z]{0,4}){0,2}",
+            padding in MIN_PADDING..0x200,
+            database in proptest::collection::vec(any::<u8>(), 0..512),
+        ) {
+            let mut path = dirs.join("\\");
+            if !path.is_empty() {
+                path.push('\\');
+            }
+            path.push_str(&name);
+            path.push_str(&ext);
+            prop_assume!(path.len() <= MAX_PATH);
+
+            let upload = parse(&body(&path, padding, &database), 512).unwrap();
+            prop_assert_eq!(upload.filename, name);
+            prop_assert_eq!(upload.data, database);
+        }
+    }
+}
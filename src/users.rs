@@ -0,0 +1,298 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, BufRead, IsTerminal, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use clap::Subcommand;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs,
+    sync::{Mutex, RwLock},
+};
+
+/// User group of developers, as the official server assigns
+pub const DEFAULT_GROUP: u32 = 76;
+
+/// Hash of no account, checked against passwords of unknown users
+static DUMMY_HASH: Lazy<String> =
+    Lazy::new(|| hash_password("dream-tutor").expect("failed to hash password"));
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Account {
+    pub uid: u32,
+    pub group: u32,
+    /// Argon2 hash in PHC string format
+    password: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Accounts {
+    /// Uids are never reused, tasks of a removed user are not inherited
+    next_uid: u32,
+    accounts: BTreeMap<String, Account>,
+}
+
+/// Accounts allowed to login, kept in a json file keyed by username
+///
+/// The file is read again on login once changed, so accounts managed by `dream-tutor user`
+/// take effect without restarting the server.
+#[derive(Debug)]
+pub struct UserRegistry {
+    path: PathBuf,
+    data: RwLock<Accounts>,
+    /// Modification time of the file as last read or written
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl UserRegistry {
+    pub async fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let modified = modified(&path).await;
+        let data = read_accounts(&path).await?;
+
+        Ok(Self {
+            path,
+            data: RwLock::new(data),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// Read the file again if another process changed it since
+    async fn reload(&self) -> io::Result<()> {
+        let mut data = self.data.write().await;
+        let mut last = self.modified.lock().await;
+        let modified = modified(&self.path).await;
+        if modified != *last {
+            *data = read_accounts(&self.path).await?;
+            *last = modified;
+        }
+        Ok(())
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.data.read().await.accounts.is_empty()
+    }
+
+    /// Get the account if the password matches
+    pub async fn verify(&self, username: &str, password: &str) -> Option<Account> {
+        if let Err(err) = self.reload().await {
+            tracing::error!("failed to reload users: {:?}", err);
+        }
+
+        let account = self.data.read().await.accounts.get(username).cloned();
+        // hashing takes long enough to stall other requests on the runtime
+        let (username, password) = (username.to_owned(), password.to_owned());
+        tokio::task::spawn_blocking(move || {
+            // unknown users are checked all the same, so that they take as long as wrong passwords
+            let hash = account
+                .as_ref()
+                .map_or(DUMMY_HASH.as_str(), |account| &account.password);
+            let verified = match PasswordHash::new(hash) {
+                Ok(hash) => Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok(),
+                Err(err) => {
+                    tracing::error!("bad password hash of {}: {}", username, err);
+                    false
+                }
+            };
+            account.filter(|_| verified)
+        })
+        .await
+        .ok()?
+    }
+
+    /// Add a new account, returns its uid
+    pub async fn add(&self, username: &str, password: &str, group: u32) -> io::Result<u32> {
+        let mut data = self.data.write().await;
+        if data.accounts.contains_key(username) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("user {} already exists", username),
+            ));
+        }
+
+        let uid = data.next_uid.max(1);
+        let account = Account {
+            uid,
+            group,
+            password: hash_password(password)?,
+        };
+        data.accounts.insert(username.to_owned(), account);
+        data.next_uid = uid + 1;
+
+        self.save(&data).await?;
+        Ok(uid)
+    }
+
+    pub async fn remove(&self, username: &str) -> io::Result<()> {
+        let mut data = self.data.write().await;
+        data.accounts
+            .remove(username)
+            .ok_or_else(|| not_found(username))?;
+        self.save(&data).await
+    }
+
+    pub async fn reset_password(&self, username: &str, password: &str) -> io::Result<()> {
+        let mut data = self.data.write().await;
+        let account = data
+            .accounts
+            .get_mut(username)
+            .ok_or_else(|| not_found(username))?;
+        account.password = hash_password(password)?;
+        self.save(&data).await
+    }
+
+    async fn save(&self, data: &Accounts) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+
+        let json = serde_json::to_vec_pretty(data).map_err(io::Error::from)?;
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, json).await?;
+        fs::rename(&tmp, &self.path).await?;
+
+        *self.modified.lock().await = modified(&self.path).await;
+        Ok(())
+    }
+}
+
+async fn read_accounts(path: &Path) -> io::Result<Accounts> {
+    match fs::read(path).await {
+        Ok(json) => serde_json::from_slice(&json).map_err(io::Error::from),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Accounts::default()),
+        Err(err) => Err(err),
+    }
+}
+
+async fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).await.and_then(|m| m.modified()).ok()
+}
+
+fn hash_password(password: &str) -> io::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|err| io::Error::other(err.to_string()))
+}
+
+fn not_found(username: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("user {} not found", username),
+    )
+}
+
+#[derive(Debug, Subcommand)]
+pub enum UserCommand {
+    /// Add a new user, whose password is read from stdin
+    Add {
+        username: String,
+        /// User group returned to the client on login
+        #[clap(long, default_value_t = DEFAULT_GROUP)]
+        group: u32,
+    },
+    /// Remove a user, tasks already submitted are kept
+    Remove { username: String },
+    /// Set a new password for a user, read from stdin
+    Reset { username: String },
+    /// List all users
+    List,
+}
+
+impl UserCommand {
+    pub async fn run(self, registry: &UserRegistry) -> io::Result<()> {
+        match self {
+            UserCommand::Add { username, group } => {
+                let password = read_password()?;
+                let uid = registry.add(&username, &password, group).await?;
+                println!("added user {} with uid {}", username, uid);
+            }
+            UserCommand::Remove { username } => {
+                registry.remove(&username).await?;
+                println!("removed user {}", username);
+            }
+            UserCommand::Reset { username } => {
+                let password = read_password()?;
+                registry.reset_password(&username, &password).await?;
+                println!("password of user {} reset", username);
+            }
+            UserCommand::List => {
+                for (username, account) in registry.data.read().await.accounts.iter() {
+                    println!("{}\tuid={}\tgroup={}", username, account.uid, account.group);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Read a password from the first line of stdin, so that it stays out of shell history and
+/// process lists
+fn read_password() -> io::Result<String> {
+    let stdin = io::stdin();
+    if stdin.is_terminal() {
+        eprint!("password: ");
+        io::stderr().flush()?;
+    }
+
+    let mut line = String::new();
+    stdin.lock().read_line(&mut line)?;
+    let password = line.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "empty password",
+        ));
+    }
+    Ok(password.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn manage_accounts() {
+        let path = std::env::temp_dir().join(format!("dream-tutor-{}.json", uuid::Uuid::new_v4()));
+        let registry = UserRegistry::open(&path).await.unwrap();
+        assert!(registry.is_empty().await);
+
+        assert_eq!(registry.add("alice", "secret", 1).await.unwrap(), 1);
+        assert_eq!(registry.add("bob", "hunter2", 2).await.unwrap(), 2);
+        assert!(registry.add("alice", "again", 1).await.is_err());
+
+        let account = registry.verify("alice", "secret").await.unwrap();
+        assert_eq!((account.uid, account.group), (1, 1));
+        assert!(registry.verify("alice", "hunter2").await.is_none());
+        assert!(registry.verify("carol", "secret").await.is_none());
+        // hashed as any other password
+        assert!(Lazy::get(&DUMMY_HASH).is_some());
+
+        registry.reset_password("bob", "changed").await.unwrap();
+        registry.remove("alice").await.unwrap();
+        assert!(registry.remove("alice").await.is_err());
+        assert!(registry.reset_password("alice", "secret").await.is_err());
+
+        // kept across restarts, and uids of removed users are not handed out again
+        let reopened = UserRegistry::open(&path).await.unwrap();
+        assert!(reopened.verify("alice", "secret").await.is_none());
+        assert_eq!(reopened.verify("bob", "changed").await.unwrap().uid, 2);
+        assert_eq!(reopened.add("carol", "pass", 3).await.unwrap(), 3);
+
+        // changes made by another process take effect without reopening
+        assert_eq!(registry.verify("carol", "pass").await.unwrap().uid, 3);
+
+        fs::remove_file(&path).await.unwrap();
+    }
+}
@@ -0,0 +1,252 @@
+use std::sync::Arc;
+
+use serde::Deserialize;
+use tokio::sync::{mpsc, Mutex};
+
+use crate::{
+    build_artifact, storage::Storage, Artifact, BuildSettings, CompileOption, CompileStatus,
+    CompileTask,
+};
+
+/// Limits of the background compilation
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueSettings {
+    /// Number of games being built at the same time
+    pub workers: usize,
+    /// Number of submitted games waiting for a free worker
+    pub depth: usize,
+}
+
+impl Default for QueueSettings {
+    fn default() -> Self {
+        Self {
+            workers: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            depth: 64,
+        }
+    }
+}
+
+/// A submitted game waiting to be built
+#[derive(Debug)]
+pub struct Job {
+    /// Owner of the task
+    pub uid: u32,
+    pub task: CompileTask,
+    pub option: CompileOption,
+    pub file: Box<[u8]>,
+}
+
+/// Queue of submitted games, built by a pool of background workers
+#[derive(Debug)]
+pub struct CompileQueue {
+    sender: mpsc::Sender<Job>,
+}
+
+impl CompileQueue {
+    /// Spawn workers on the current runtime
+    pub fn start(
+        settings: &QueueSettings,
+        storage: Arc<dyn Storage>,
+        build: Arc<BuildSettings>,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel(settings.depth.max(1));
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for worker in 0..settings.workers.max(1) {
+            let receiver = receiver.clone();
+            let storage = storage.clone();
+            let build = build.clone();
+            tokio::spawn(async move {
+                loop {
+                    // only one idle worker waits on the channel at a time
+                    let job = receiver.lock().await.recv().await;
+                    match job {
+                        Some(job) => run(job, storage.as_ref(), build.clone()).await,
+                        None => break,
+                    }
+                }
+                tracing::debug!("compile worker {} stopped", worker);
+            });
+        }
+
+        Self { sender }
+    }
+
+    /// Enqueue a job, give it back if the queue is full
+    pub fn submit(&self, job: Job) -> Result<(), Box<Job>> {
+        self.sender.try_send(job).map_err(|err| match err {
+            mpsc::error::TrySendError::Full(job) | mpsc::error::TrySendError::Closed(job) => {
+                Box::new(job)
+            }
+        })
+    }
+}
+
+#[tracing::instrument(skip_all, fields(id = job.task.id))]
+async fn run(job: Job, storage: &dyn Storage, build: Arc<BuildSettings>) {
+    let Job {
+        uid,
+        mut task,
+        option,
+        file,
+    } = job;
+
+    // LuaJIT work would block the runtime, so leave it to the blocking pool
+    let build_time = build.build_time(task.addtime);
+    let (id, token) = (task.id, task.report_token.clone());
+    let Artifact {
+        result,
+        update,
+        warnings,
+    } = tokio::task::spawn_blocking(move || {
+        let report = Some((id, token.as_str()));
+        build_artifact(&file, &option, build_time, Some(uid), report, &build)
+    })
+    .await
+    .unwrap_or_else(|err| {
+        tracing::error!("compile panicked: {:?}", err);
+        Artifact {
+            result: Err("编译时发生内部错误".to_owned()),
+            update: None,
+            warnings: Vec::new(),
+        }
+    });
+    task.warnings = warnings;
+
+    // publish resources to auto update games only if the whole build succeeded
+    let result = match (result, update) {
+        (Ok(packed), Some(files)) => storage
+            .store_update(uid, &task.filename, task.ver, files)
+            .await
+            .map(|_| packed)
+            .map_err(|err| {
+                tracing::error!("store update error: {:?}", err);
+                "发布更新失败".to_owned()
+            }),
+        (result, _) => result,
+    };
+
+    task.status = match result {
+        Ok(_) => CompileStatus::Done,
+        Err(_) => CompileStatus::Failed,
+    };
+
+    if let Err(err) = storage.store_result(task.id, result).await {
+        tracing::error!("store result error: {:?}", err);
+        task.status = CompileStatus::Failed;
+    }
+    if let Err(err) = storage.store_task(uid, task).await {
+        tracing::error!("store task error: {:?}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{now, storage::MemoryStorage, GameType};
+
+    fn job(id: u32, source: &str) -> Job {
+        let mut file = vec![0; 0x200];
+        file.extend_from_slice(source.as_bytes());
+        Job {
+            uid: 1,
+            task: CompileTask {
+                id,
+                filename: "123".to_owned(),
+                addtime: now(),
+                status: CompileStatus::Processing,
+                op_login: GameType::Offline,
+                op_qudong: false,
+                ver: 1,
+                header: None,
+                warnings: Vec::new(),
+                report_token: String::new(),
+            },
+            option: CompileOption {
+                name: "123".to_owned(),
+                filename: "123".to_owned(),
+                op_safedata: false,
+                op_delad: false,
+                op_statistics: false,
+                op_jiasu: false,
+                op_keywords: String::new(),
+                op_qudong: false,
+                op_login: GameType::Offline,
+                ver: 1,
+            },
+            file: file.into_boxed_slice(),
+        }
+    }
+
+    fn start(workers: usize, depth: usize) -> (CompileQueue, Arc<dyn Storage>) {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        let settings = QueueSettings { workers, depth };
+        let build = Arc::new(BuildSettings::default());
+        (
+            CompileQueue::start(&settings, storage.clone(), build),
+            storage,
+        )
+    }
+
+    /// Submit `job` as the server does, and wait for a worker to finish it
+    async fn build(queue: &CompileQueue, storage: &dyn Storage, job: Job) -> CompileTask {
+        let id = job.task.id;
+        storage.store_task(job.uid, job.task.clone()).await.unwrap();
+        queue.submit(job).unwrap();
+
+        let finished = async {
+            loop {
+                let tasks = storage.load_tasks(1).await.unwrap();
+                match tasks.get(&id) {
+                    Some(task) if !matches!(task.status, CompileStatus::Processing) => {
+                        return task.clone()
+                    }
+                    _ => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(30), finished)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn finish_built_games() {
+        if !crate::luajit_2_0() {
+            return;
+        }
+        let (queue, storage) = start(2, 4);
+        let task = build(&queue, storage.as_ref(), job(0, "local a = 1")).await;
+        assert!(matches!(task.status, CompileStatus::Done));
+
+        let packed = storage.load_result(0).await.unwrap().unwrap().unwrap();
+        assert!(dream_tutor::crypto::is_compressed(&packed));
+    }
+
+    #[tokio::test]
+    async fn fail_broken_games() {
+        let (queue, storage) = start(2, 4);
+        let task = build(&queue, storage.as_ref(), job(0, "local = 2")).await;
+        assert!(matches!(task.status, CompileStatus::Failed));
+        assert!(storage.load_result(0).await.unwrap().unwrap().is_err());
+
+        // the worker is still there for later games
+        let mut broken = job(1, "");
+        broken.file = Box::new([0; 0x10]);
+        let task = build(&queue, storage.as_ref(), broken).await;
+        assert!(matches!(task.status, CompileStatus::Failed));
+        assert!(storage.load_result(1).await.unwrap().unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn reject_jobs_beyond_depth() {
+        // workers of a single threaded runtime take no job before the test awaits
+        let (queue, _) = start(1, 1);
+        queue.submit(job(0, "local a = 1")).unwrap();
+        let rejected = queue.submit(job(1, "local a = 1")).unwrap_err();
+        assert_eq!(rejected.task.id, 1);
+    }
+}
@@ -0,0 +1,25 @@
+local f1 = 核心.数据统计
+核心.数据统计 = function(gid, uid, unk, hash, time)
+    if {{statistics}} then
+        f1({{gid}}, {{uid}}, 0, {{hash}}, {{time}})
+        -- count the launch on the build server too, 核心.读取网址 is set up by Sys.lua by now
+        if {{report_url}} ~= nil and type(核心.读取网址) == "function" then
+            核心.读取网址({{report_url}}, function() end)
+        end
+    end
+end
+-- run once Sys.lua has set up Sys, which it reports to 核心.anti_hacking right after
+local f2, on_sys = 核心.anti_hacking, {}
+核心.anti_hacking = function(enabled, keywords)
+    if type(Sys) == "table" then
+        for _, f in ipairs(on_sys) do
+            f(Sys)
+        end
+        on_sys = {}
+    end
+    f2(1, {{keywords}})
+end
+-- Sys.lua quits games started by a runtime whose driver setting differs
+on_sys[#on_sys + 1] = function(Sys)
+    Sys.op_qudong = {{qudong}}
+end
@@ -0,0 +1,9 @@
+local cheat_response = {{response}}
+-- exits or raises unless the caller is to undo the cheat
+local function cheat_detected(message)
+    if cheat_response == "exit" then
+        os.exit(1)
+    elseif cheat_response == "error" then
+        error(message, 0)
+    end
+end
@@ -0,0 +1,29 @@
+-- Sys.lua takes the account of login games from 核心.get_info as they start
+on_sys[#on_sys + 1] = function(Sys)
+    Sys.op_login = 1
+end
+local function escape(s)
+    return (tostring(s):gsub("[^%w%-_%.~]", function(c)
+        return string.format("%%%02X", c:byte())
+    end))
+end
+-- ask the login server whether it knows the account, and quit unless it answers 1
+local function check_account(uname, uuid, ...)
+    -- 核心.读取网址 is set up by Sys.lua, and calls back once the answer arrives
+    if uname ~= nil and uuid ~= nil and type(核心.读取网址) == "function" then
+        local url = "http://" .. {{host}} .. ":" .. {{port}} .. "/login?uname=" .. escape(uname)
+            .. "&uuid=" .. escape(uuid)
+        核心.读取网址(url, function(answer)
+            if answer ~= "1" then
+                os.exit()
+            end
+        end)
+    end
+    return uname, uuid, ...
+end
+local f3 = 核心.get_info
+if type(f3) == "function" then
+    核心.get_info = function(...)
+        return check_account(f3(...))
+    end
+end
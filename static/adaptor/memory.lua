@@ -0,0 +1,195 @@
+-- sensitive values are kept out of their tables, in two copies xored with random keys and a
+-- checksum, so memory scanners find neither the values nor anything worth changing
+local slots = setmetatable({}, { __mode = "k" })
+
+local function encode(v)
+    if type(v) ~= "number" or v ~= v or math.abs(v) >= 2 ^ 53 then
+        return { plain = v }
+    end
+    local k1, k2 = math.random(0, 0x7fffffff), math.random(0, 0x7fffffff)
+    if v ~= math.floor(v) then
+        -- scaling by a power of two is exact but moves the exponent
+        local shift = k1 % 16 + 1
+        return { scaled = v / 2 ^ shift, shift = shift }
+    end
+    -- bit works on 32 bits, integers of doubles have 53
+    local hi = math.floor(v / 2 ^ 32)
+    return { bit.bxor(v - hi * 2 ^ 32, k1), bit.bxor(hi, k2), k1, k2 }
+end
+
+local function decode(e)
+    if e.shift then
+        return e.scaled * 2 ^ e.shift
+    elseif e[1] then
+        return bit.bxor(e[2], e[4]) * 2 ^ 32 + bit.bxor(e[1], e[3]) % 2 ^ 32
+    end
+    return e.plain
+end
+
+local function checksum(v, seed)
+    local s, h = tostring(v), seed
+    for i = 1, #s do
+        h = bit.bxor(bit.rol(h, 5), s:byte(i))
+    end
+    return h
+end
+
+local function store_slot(slot, v)
+    slot.value, slot.shadow = encode(v), encode(v)
+    slot.seed = math.random(0, 0x7fffffff)
+    slot.sum = checksum(v, slot.seed)
+end
+
+local function load_slot(slot)
+    local value, shadow = decode(slot.value), decode(slot.shadow)
+    if checksum(value, slot.seed) == slot.sum and checksum(shadow, slot.seed) == slot.sum then
+        return value
+    end
+    -- keep the copy the checksum still vouches for, and reset the value once neither is left
+    if checksum(value, slot.seed) ~= slot.sum then
+        if checksum(shadow, slot.seed) == slot.sum then
+            value = shadow
+        else
+            value = 0
+        end
+    end
+    -- restored before it is reported, so the change is reported once
+    store_slot(slot, value)
+    cheat_detected("检测到内存修改")
+    return value
+end
+
+-- fields left in `t` come first, then the protected ones, but for those set in `t` by rawset
+local function protected_next(protected)
+    return function(t, k)
+        if k == nil or rawget(t, k) ~= nil then
+            local key, v = next(t, k)
+            if key ~= nil then
+                return key, v
+            end
+            k = nil
+        end
+        local key, slot = next(protected, k)
+        while key ~= nil and rawget(t, key) ~= nil do
+            key, slot = next(protected, key)
+        end
+        if key ~= nil then
+            return key, load_slot(slot)
+        end
+    end
+end
+
+-- protect fields `...` of `t`, which are still read and written as any other field
+function 保护数据(t, ...)
+    local protected = slots[t]
+    if not protected then
+        protected = {}
+        -- the metatable may be shared by other tables, so extend a copy of it
+        local mt, old = {}, getmetatable(t)
+        if type(old) == "table" then
+            for k, v in next, old do
+                mt[k] = v
+            end
+        end
+        local index, newindex = mt.__index, mt.__newindex
+        mt.__index = function(t, k)
+            local slot = protected[k]
+            if slot then
+                return load_slot(slot)
+            elseif type(index) == "function" then
+                return index(t, k)
+            elseif index then
+                return index[k]
+            end
+        end
+        mt.__newindex = function(t, k, v)
+            local slot = protected[k]
+            if slot then
+                store_slot(slot, v)
+            elseif type(newindex) == "function" then
+                newindex(t, k, v)
+            elseif newindex then
+                newindex[k] = v
+            else
+                rawset(t, k, v)
+            end
+        end
+        -- libraries copy and save tables by pairs. next, rawget and rawset only see the fields
+        -- left in the table
+        mt.__pairs = function(t)
+            return protected_next(protected), t, nil
+        end
+        -- tables with a locked metatable are left alone
+        if not pcall(setmetatable, t, mt) then
+            return t
+        end
+        slots[t] = protected
+    end
+
+    for i = 1, select("#", ...) do
+        local k = select(i, ...)
+        if not protected[k] then
+            local slot = {}
+            store_slot(slot, rawget(t, k))
+            rawset(t, k, nil)
+            protected[k] = slot
+        end
+    end
+    return t
+end
+
+-- LuaJIT only looks for __pairs when built with Lua 5.2 compatibility, elsewhere pairs looks it up
+-- on protected tables, which costs a lookup once per loop rather than each step
+local honours_pairs = false
+local probe = setmetatable({}, { __pairs = function()
+    honours_pairs = true
+    return next, {}, nil
+end })
+for _ in pairs(probe) do
+end
+if not honours_pairs then
+    local raw_pairs = pairs
+    function pairs(t)
+        local mt = slots[t] and getmetatable(t)
+        if type(mt) == "table" and mt.__pairs then
+            return mt.__pairs(t)
+        end
+        return raw_pairs(t)
+    end
+end
+
+-- gold, HP and experience of the player, in 属性 of instances of the class registered as hero
+local function protect_player(player)
+    if type(player) == "table" and type(player.属性) == "table" then
+        保护数据(player.属性, "金币", "hp", "经验值")
+    end
+end
+
+local function protect_after(method)
+    local function pass(self, ...)
+        protect_player(self)
+        return ...
+    end
+    return function(self, ...)
+        return pass(self, method(self, ...))
+    end
+end
+
+-- the hero class is registered to Sys after it is set up
+on_sys[#on_sys + 1] = function(Sys)
+    if type(Sys.add) ~= "function" then
+        return
+    end
+    local add = Sys.add
+    Sys.add = function(name, class, ...)
+        if name == "hero" and type(class) == "table" then
+            -- 属性 is set up by 初始化, and may be replaced by 属性更新
+            for _, method in ipairs({ "初始化", "属性更新" }) do
+                if type(class[method]) == "function" then
+                    class[method] = protect_after(class[method])
+                end
+            end
+        end
+        return add(name, class, ...)
+    end
+end
@@ -0,0 +1,45 @@
+-- animations and cooldowns are timed by 引擎.取运行时间 in milliseconds, which speed hacks run
+-- ahead of os.time as they leave the wall clock alone
+if type(引擎) == "table" and type(引擎.取运行时间) == "function" then
+    local ticks, wall = 引擎.取运行时间, os.time
+    -- ticks are sampled as os.time turns to the next second and compared with the samples of the
+    -- last few seconds. os.time has whole seconds, so over a span of 3 of them real time is off by
+    -- less than a third, which the tolerance covers
+    local window, span, tolerance = 5, 3, 1.5
+    local samples = { { tick = ticks(), time = wall() } }
+    -- last ticks of the runtime, ticks handed out, and how much faster than real time to undo
+    local last, corrected, rate = samples[1].tick, samples[1].tick, 1
+    引擎.取运行时间 = function(...)
+        local tick, now = ticks(...), wall()
+        if tick < last or now < samples[#samples].time then
+            -- a clock went back, compare from here on
+            samples = { { tick = tick, time = now } }
+        else
+            corrected = corrected + (tick - last) / rate
+        end
+        last = tick
+
+        local detected = false
+        if now > samples[#samples].time then
+            samples[#samples + 1] = { tick = tick, time = now }
+            while now - samples[1].time > window do
+                table.remove(samples, 1)
+            end
+            local elapsed = now - samples[1].time
+            if elapsed >= span then
+                local speed = (tick - samples[1].tick) / (elapsed * 1000)
+                if speed > tolerance then
+                    -- reported as the hack is noticed, and only undone while it goes on
+                    detected = rate == 1
+                    rate = speed
+                else
+                    rate = 1
+                end
+            end
+        end
+        if detected then
+            cheat_detected("检测到加速外挂")
+        end
+        return math.floor(corrected)
+    end
+end
@@ -0,0 +1,16 @@
+-- Sys.lua quits games started by a runtime of another game type
+on_sys[#on_sys + 1] = function(Sys)
+    Sys.op_login = 2
+end
+-- ask for the latest manifest as the game starts, and tell the game if there is a newer version
+on_sys[#on_sys + 1] = function(Sys)
+    -- 核心.读取网址 is set up by Sys.lua, and calls back once the answer arrives
+    if type(核心.读取网址) == "function" then
+        核心.读取网址({{url}} .. "/manifest", function(manifest)
+            local ver = tonumber(string.match(tostring(manifest), '"ver":(%d+)'))
+            if ver ~= nil and ver > {{ver}} and type(发现新版本) == "function" then
+                发现新版本(ver, {{url}} .. "/" .. ver .. "/")
+            end
+        end)
+    end
+end